@@ -0,0 +1,116 @@
+use super::*;
+use anyhow::Context;
+
+pub const USAGE: &str = "\
+Usage:
+    kobold_cli - Start the interactive prompt.
+    kobold_cli gen <file> --char <name> [--n <count>] [--stdout [--null]] - Generate as <name>
+        from <file>, write the response back to the file and exit. With --n, generate <count>
+        responses and write the first. With --stdout, print every response instead, each
+        followed by a newline, and print everything else to stderr. With --null, end each
+        response with a NUL character instead of a newline.
+    kobold_cli serve --stdio - Speak JSON-RPC over stdin and stdout, one message per line, so
+        that editor plugins can load files and generate. Everything else is printed to stderr.
+    kobold_cli check <file>... - Check prompt files for mistakes. Exits with an error if any
+        are found.
+    kobold_cli mock-server [--port <port>] [--delay <ms>] [--fail <count>] [--stream]
+        [<response>...] - Serve a fake KoboldCpp API, for trying out prompt files and plugins
+        without a model. Generates each <response> in turn, repeating the last, at one word
+        every <ms> milliseconds, after failing the first <count> requests. With --stream, it
+        also serves streamed generations. Listens on port 5001 by default.
+    kobold_cli help - Display this help text.
+";
+
+/// The options of the `gen` subcommand.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenArgs {
+    pub(crate) file: String,
+    pub(crate) character: String,
+    pub(crate) count: usize,
+    pub(crate) stdout: bool,
+    /// Ends printed responses with NUL characters instead of newlines.
+    pub(crate) null: bool,
+}
+
+impl GenArgs {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut file = None;
+        let mut character = None;
+        let mut count = 1;
+        let mut stdout = false;
+        let mut null = false;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .with_context(|| format!("{flag} requires a value"))
+            };
+
+            match flag {
+                "--char" => character = Some(value()?),
+                "--n" => {
+                    let n = value()?;
+                    count = match n.parse() {
+                        Ok(0) | Err(_) => bail!("--n must be a positive number, not {n:?}"),
+                        Ok(count) => count,
+                    };
+                }
+                "--stdout" => stdout = true,
+                "--null" => null = true,
+                _ if flag.starts_with("--") => bail!("Unknown option {flag}"),
+                _ if file.is_none() => file = Some(arg.clone()),
+                _ => bail!("Unexpected argument {arg:?}"),
+            }
+        }
+
+        if null && !stdout {
+            bail!("--null requires --stdout");
+        }
+
+        Ok(GenArgs {
+            file: file.context("gen requires a prompt file")?,
+            character: character.context("gen requires a character, given with --char")?,
+            count,
+            stdout,
+            null,
+        })
+    }
+}
+
+/// What to do, according to the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Args {
+    Repl,
+    Help,
+    Gen(GenArgs),
+    Serve,
+    Check(Vec<String>),
+    MockServer(Vec<String>),
+}
+
+impl Args {
+    /// Parses the command line arguments, not including the program name.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let rest = args.get(1..).unwrap_or_default();
+
+        Ok(match args.first().map(String::as_str) {
+            None => Args::Repl,
+            Some("help" | "--help" | "-h") => Args::Help,
+            Some("gen") => Args::Gen(GenArgs::parse(rest)?),
+            Some("serve") => match rest {
+                [flag] if flag == "--stdio" => Args::Serve,
+                _ => bail!("serve requires --stdio"),
+            },
+            Some("check") => Args::Check(rest.to_vec()),
+            Some("mock-server") => Args::MockServer(rest.to_vec()),
+            Some(command) => bail!("Unknown command {command:?}"),
+        })
+    }
+}
@@ -0,0 +1,249 @@
+use super::*;
+
+/// How a prompt was fit into the server's context window.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    /// Tokens in the prompt which is sent.
+    pub(crate) tokens: usize,
+    /// Tokens available for the prompt: the context size minus the tokens to generate.
+    pub(crate) available: usize,
+    /// Tokens reserved for the response.
+    pub(crate) reserved: usize,
+    pub(crate) dropped_lines: usize,
+    pub(crate) dropped_tokens: usize,
+    /// Whether the counts are estimates, because the server can't tokenize text.
+    pub(crate) estimated: bool,
+}
+
+impl Budget {
+    /// Summarizes the budget in one line.
+    pub fn summary(&self) -> String {
+        let approx = if self.estimated { "~" } else { "" };
+        format!(
+            "Prompt: {approx}{}/{} tokens, {approx}{} left, {} reserved for the response.",
+            self.tokens,
+            self.available,
+            self.available.saturating_sub(self.tokens),
+            self.reserved
+        )
+    }
+
+    /// Describes the lines which were dropped, if any.
+    pub fn report(&self) -> Option<String> {
+        let approx = if self.estimated { "~" } else { "" };
+
+        if self.tokens > self.available {
+            return Some(format!(
+                "Warning: the context and prefix alone take {approx}{} tokens, but only {} are \
+                available for the prompt.",
+                self.tokens, self.available
+            ));
+        }
+
+        if self.dropped_lines == 0 {
+            return None;
+        }
+
+        Some(format!(
+            "Dropped the oldest {} lines ({approx}{} tokens) of the prompt to fit the context.",
+            self.dropped_lines, self.dropped_tokens
+        ))
+    }
+}
+
+/// Counts tokens with a pool's tokenizer, estimating if it can't tokenize.
+struct Counter<'a> {
+    servers: &'a mut Servers,
+    pool: &'a str,
+    estimated: bool,
+}
+
+impl Counter<'_> {
+    async fn count(&mut self, text: &str) -> Result<usize> {
+        match self.servers.count_tokens(self.pool, text).await? {
+            Some(tokens) => Ok(tokens),
+            None => {
+                self.estimated = true;
+                // Most tokenizers average about 4 characters per token in English.
+                Ok(text.chars().count().div_ceil(4))
+            }
+        }
+    }
+
+    /// Returns the first of `cuts` at which `parts` fit in `available` tokens, along with the
+    /// token count there, assuming that the count only decreases along `cuts`.
+    async fn first_fit(
+        &mut self,
+        parts: &PromptParts,
+        cuts: &[usize],
+        available: usize,
+    ) -> Result<Option<(usize, usize)>> {
+        let (mut lo, mut hi) = (0, cuts.len());
+        let mut best = None;
+
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let tokens = self.count(&parts.join(cuts[mid])).await?;
+
+            if tokens <= available {
+                best = Some((cuts[mid], tokens));
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+
+        Ok(best)
+    }
+}
+
+/// Builds the prompt for `parts`, dropping the oldest lines of the chat until it fits in the
+/// context window of `server_prompt`, while leaving room for the response. Whole turns of
+/// `speakers` are dropped if possible.
+pub(super) async fn fit_to_context(
+    servers: &mut Servers,
+    pool: &str,
+    parts: &PromptParts,
+    speakers: &[&str],
+    server_prompt: &ServerPrompt,
+) -> Result<(String, Budget)> {
+    let mut counter = Counter {
+        servers,
+        pool,
+        estimated: false,
+    };
+    let available = server_prompt
+        .max_context_length
+        .saturating_sub(server_prompt.max_length);
+    let total = counter.count(&parts.join(0)).await?;
+
+    let mut budget = Budget {
+        tokens: total,
+        available,
+        reserved: server_prompt.max_length,
+        ..Budget::default()
+    };
+
+    if total > available {
+        let (lines, turns) = parts.cut_points(speakers);
+
+        // The last cut point drops the whole chat, so it is used if nothing else fits.
+        let fit = match counter.first_fit(parts, &turns, available).await? {
+            Some(fit) if fit.0 < parts.chat.len() => Some(fit),
+            _ => counter.first_fit(parts, &lines, available).await?,
+        };
+        let (cut, tokens) = match fit {
+            Some(fit) => fit,
+            None => {
+                let cut = parts.chat.len();
+                (cut, counter.count(&parts.join(cut)).await?)
+            }
+        };
+
+        budget.tokens = tokens;
+        budget.dropped_lines = parts.chat[..cut].lines().count();
+        budget.dropped_tokens = total.saturating_sub(tokens);
+        budget.estimated = counter.estimated;
+
+        return Ok((parts.join(cut), budget));
+    }
+
+    budget.estimated = counter.estimated;
+    Ok((parts.join(0), budget))
+}
+
+/// Returns the prompt to generate as `character` on its pool, fit to the context window.
+pub(super) async fn server_prompt_for(
+    servers: &mut Servers,
+    prompt: &Prompt,
+    character: &str,
+) -> Result<ServerPrompt> {
+    let mut server_prompt = prompt.get_server_prompt(character)?;
+    servers.check_prompt(prompt.pool(character)?, &server_prompt)?;
+
+    let (fitted, budget) = fit_to_context(
+        servers,
+        prompt.pool(character)?,
+        &prompt.prompt_parts(character)?,
+        &prompt.speakers(),
+        &server_prompt,
+    )
+    .await?;
+
+    println!("{}", budget.summary());
+    if let Some(report) = budget.report() {
+        println!("{report}");
+    }
+
+    server_prompt.prompt = fitted;
+    Ok(server_prompt)
+}
+
+impl Cli {
+    /// Prints how many tokens each part of the prompt for the current character takes, and how
+    /// much of the context is left.
+    pub async fn tokens(&mut self) -> Result<()> {
+        self.reload_file().await?;
+
+        let Some(prompt) = self.prompt.as_ref() else {
+            bail!("No file loaded!")
+        };
+        let Some(character) = self.character.as_ref() else {
+            bail!("No character selected!")
+        };
+        let Some(servers) = self.servers.as_mut() else {
+            bail!("No servers initialized!")
+        };
+
+        let server_prompt = prompt.get_server_prompt(character)?;
+        let parts = prompt.prompt_parts(character)?;
+        let pool = prompt.pool(character)?;
+        let mut counter = Counter {
+            servers,
+            pool,
+            estimated: false,
+        };
+
+        let total = counter.count(&parts.join(0)).await?;
+        let rows = [
+            ("Context", counter.count(&parts.context).await?),
+            ("Chat", counter.count(&parts.chat).await?),
+            ("Prefix", counter.count(&parts.prefix).await?),
+            ("Total", total),
+        ];
+        let approx = if counter.estimated { "~" } else { "" };
+
+        for (name, tokens) in rows {
+            println!("{name:<10}{approx}{tokens}");
+        }
+
+        let available = server_prompt
+            .max_context_length
+            .saturating_sub(server_prompt.max_length);
+
+        println!(
+            "Available {available} (max_context_length {} - max_length {})",
+            server_prompt.max_context_length, server_prompt.max_length
+        );
+        println!("Left      {approx}{}", available as i64 - total as i64);
+
+        let (_, budget) = fit_to_context(
+            counter.servers,
+            pool,
+            &parts,
+            &prompt.speakers(),
+            &server_prompt,
+        )
+        .await?;
+
+        if counter.estimated {
+            println!("The server can't tokenize text, so counts are estimated.");
+        }
+
+        if let Some(report) = budget.report() {
+            println!("{report}");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+use super::*;
+
+use std::collections::{BTreeSet, HashMap};
+
+/// The placeholders replaced in characters, contexts and the prompt.
+const PLACEHOLDERS: [&str; 2] = ["{{char}}", "{{user}}"];
+
+/// Collects problems found in a prompt file, along with where they were found.
+struct Lints<'a> {
+    prompt: &'a Prompt,
+    text: &'a str,
+    found: Vec<(usize, Diagnostic)>,
+}
+
+impl Lints<'_> {
+    fn add(&mut self, offset: usize, message: impl Into<String>) {
+        let diagnostic = self.prompt.source.diagnostic(offset, message);
+        self.found.push((offset, diagnostic));
+    }
+
+    /// Returns the offset of the first line in `span` which sets `key`, or the start of `span`.
+    fn field(&self, span: Span, key: &str) -> usize {
+        let mut offset = span.start;
+
+        for line in self.text[span.start..span.end].split_inclusive('\n') {
+            let trimmed = line.trim_start();
+
+            if trimmed
+                .strip_prefix(key)
+                .is_some_and(|rest| rest.starts_with(':'))
+            {
+                return offset + line.len() - trimmed.len();
+            }
+            offset += line.len();
+        }
+
+        span.start
+    }
+
+    /// Returns the offset of the first `needle` in `span`, or the start of `span`.
+    fn find(&self, span: Span, needle: &str) -> usize {
+        self.text[span.start..span.end]
+            .find(needle)
+            .map_or(span.start, |i| span.start + i)
+    }
+
+    fn characters(&mut self) {
+        let prompt = self.prompt;
+        let mut first_spans: HashMap<&str, Span> = HashMap::new();
+
+        for (char, &span) in prompt.characters.iter().zip(&prompt.source.characters) {
+            match first_spans.get(char.name.as_str()) {
+                Some(first) => self.add(
+                    self.field(span, "name"),
+                    format!(
+                        "Character \"{}\" is already defined at {}",
+                        char.name,
+                        prompt.source.locate(first.start)
+                    ),
+                ),
+                None => {
+                    first_spans.insert(&char.name, span);
+                }
+            }
+
+            if let Some(context) = &char.context {
+                if !prompt.contexts.contains_key(context) {
+                    self.add(
+                        self.field(span, "context"),
+                        format!(
+                            "Character \"{}\" uses context \"{context}\", which is not defined",
+                            char.name
+                        ),
+                    );
+                }
+            }
+
+            for stop in &char.stop_sequence {
+                if stop.is_empty() {
+                    self.add(
+                        self.field(span, "stop_sequence"),
+                        format!(
+                            "Character \"{}\" has an empty stop sequence, which matches every \
+                            response, so its other stop sequences are never stripped",
+                            char.name
+                        ),
+                    );
+                } else if let Some(first) = char
+                    .stop_sequence
+                    .iter()
+                    .find(|other| !other.is_empty() && *other != stop && stop.contains(*other))
+                {
+                    self.add(
+                        self.field(span, "stop_sequence"),
+                        format!(
+                            "Stop sequence {stop:?} of \"{}\" can never match, as it contains \
+                            {first:?}, which stops generation first",
+                            char.name
+                        ),
+                    );
+                }
+            }
+
+            for placeholder in PLACEHOLDERS {
+                if char.name.contains(placeholder) {
+                    self.add(
+                        self.field(span, "name"),
+                        format!("{placeholder} is not replaced in character names"),
+                    );
+                }
+                if char
+                    .grammar
+                    .as_ref()
+                    .is_some_and(|g| g.contains(placeholder))
+                {
+                    self.add(
+                        self.field(span, "grammar"),
+                        format!(
+                            "{placeholder} is not replaced in the grammar of \"{}\"",
+                            char.name
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn config(&mut self) {
+        let prompt = self.prompt;
+        let span = prompt.source.config.unwrap_or_default();
+
+        if !prompt.config.prompt.stop_sequence.is_empty() {
+            self.add(
+                self.field(span, "stop_sequence"),
+                "stop_sequence in the prompt settings is never used; set it on each character \
+                instead",
+            );
+        }
+
+        for placeholder in PLACEHOLDERS {
+            if prompt.config.user_name.contains(placeholder) {
+                self.add(
+                    self.field(span, "user_name"),
+                    format!("{placeholder} is not replaced in user_name"),
+                );
+            }
+            if prompt.config.prompt.grammar.contains(placeholder) {
+                self.add(
+                    self.field(span, "grammar"),
+                    format!("{placeholder} is not replaced in the grammar"),
+                );
+            }
+        }
+
+        // Every character shares the sampler settings, so they are checked once for each pool.
+        let configs = match prompt.config.server_configs() {
+            Ok(configs) => configs,
+            Err(e) => return self.add(span.start, e.to_string()),
+        };
+        let mut pools = BTreeSet::new();
+        let mut errors = BTreeSet::new();
+
+        for (char, &char_span) in prompt.characters.iter().zip(&prompt.source.characters) {
+            let pool = char.pool.as_deref().unwrap_or(DEFAULT_POOL);
+
+            if !configs.contains_key(pool) {
+                self.add(
+                    self.field(char_span, "pool"),
+                    format!(
+                        "Character \"{}\" uses pool \"{pool}\", which is not defined",
+                        char.name
+                    ),
+                );
+            } else if pools.insert(pool) {
+                if let Err(e) = check_prompt(&configs[pool], &prompt.config.prompt) {
+                    errors.insert(e.to_string());
+                }
+            }
+        }
+
+        let keys = match serde_json::to_value(&prompt.config.prompt) {
+            Ok(serde_json::Value::Object(keys)) => keys,
+            _ => Default::default(),
+        };
+
+        for error in errors {
+            // Point at the first setting the error mentions.
+            let key = error
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .find(|word| keys.contains_key(*word));
+            let offset = key.map_or(span.start, |key| self.field(span, key));
+            self.add(offset, error);
+        }
+    }
+
+    /// Checks that every `<|INCLUDE|>` can be read, and that what it includes is used.
+    fn includes(&mut self) {
+        let prompt = self.prompt;
+        let source = &prompt.source;
+        let used_contexts: BTreeSet<&str> = prompt
+            .characters
+            .iter()
+            .filter_map(|char| char.context.as_deref())
+            .collect();
+        let used: Vec<Span> = source
+            .config
+            .iter()
+            .chain(&source.characters)
+            .chain(
+                source
+                    .contexts
+                    .iter()
+                    .filter(|(name, _)| used_contexts.contains(name.as_str()))
+                    .map(|(_, span)| span),
+            )
+            .chain(&source.prompt)
+            .copied()
+            .collect();
+        let mut reported: Vec<Span> = Vec::new();
+
+        for include in &source.includes {
+            let span = include.span;
+            let path = include.path.display();
+
+            if !include.resolved {
+                self.found.push((
+                    span.start,
+                    Diagnostic {
+                        location: include.location.clone(),
+                        message: format!("Included file {path} does not exist"),
+                    },
+                ));
+                continue;
+            }
+
+            let text = &self.text[span.start..span.end];
+            let overlaps = |other: &Span| other.start < span.end && span.start < other.end;
+
+            if text.trim().is_empty()
+                || used.iter().any(overlaps)
+                || reported
+                    .iter()
+                    .any(|outer| outer.start <= span.start && span.end <= outer.end)
+            {
+                continue;
+            }
+
+            self.found.push((
+                span.start,
+                Diagnostic {
+                    location: include.location.clone(),
+                    message: format!(
+                        "{path} is included outside of any section or used context, so it is \
+                        never used"
+                    ),
+                },
+            ));
+            reported.push(span);
+        }
+    }
+
+    /// Checks `{{...}}` placeholders in the text of the prompt file.
+    fn placeholders(&mut self) {
+        let text = self.text;
+        let mut pos = 0;
+
+        while let Some(i) = text[pos..].find("{{") {
+            let start = pos + i;
+            pos = start + 2;
+
+            let line_end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+            let Some(end) = text[start..line_end].find("}}") else {
+                continue;
+            };
+            let placeholder = &text[start..start + end + 2];
+
+            if !PLACEHOLDERS.contains(&placeholder) {
+                self.add(
+                    start,
+                    format!(
+                        "Unknown placeholder {placeholder}, only {} are replaced",
+                        PLACEHOLDERS.join(" and ")
+                    ),
+                );
+            }
+        }
+
+        if let Some(span) = self.prompt.source.prompt {
+            if self.text[span.start..span.end].contains("{{char}}") {
+                self.add(
+                    self.find(span, "{{char}}"),
+                    "{{char}} in the prompt is replaced by whichever character generates next",
+                );
+            }
+        }
+    }
+}
+
+/// Checks the prompt file at `path` for mistakes which don't stop it from loading. Returns an
+/// error if it can't be loaded at all.
+pub fn check_file(path: impl AsRef<Path>) -> Result<Vec<Diagnostic>> {
+    let (text, source) = preprocess_leniently(&path)?;
+    let mut prompt = Prompt::parse(&text, source)?;
+    let unread_grammars = read_grammar_files(&mut prompt);
+
+    let mut lints = Lints {
+        prompt: &prompt,
+        text: &text,
+        found: Vec::new(),
+    };
+
+    for (i, e) in unread_grammars {
+        let span = prompt.source.characters[i];
+        lints.add(lints.field(span, "grammar_file"), e.to_string());
+    }
+
+    lints.config();
+    lints.characters();
+    lints.includes();
+    lints.placeholders();
+
+    let mut found = lints.found;
+    found.sort_by_key(|(offset, _)| *offset);
+
+    Ok(prompt
+        .warnings
+        .iter()
+        .cloned()
+        .chain(found.into_iter().map(|(_, diagnostic)| diagnostic))
+        .collect())
+}
+
+/// Checks each of `files`, printing the problems found. Returns whether there were none.
+pub fn check_files(files: &[String]) -> Result<bool> {
+    if files.is_empty() {
+        bail!("Usage: kobold_cli check <file>...");
+    }
+
+    let mut problems = 0;
+
+    for file in files {
+        match check_file(file) {
+            Ok(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    println!("{diagnostic}");
+                }
+                problems += diagnostics.len();
+            }
+            Err(e) => {
+                println!("Could not load {file}: {e:#}");
+                problems += 1;
+            }
+        }
+    }
+
+    if problems > 0 {
+        println!("Found {problems} problem(s) in {} file(s).", files.len());
+    }
+
+    Ok(problems == 0)
+}
@@ -4,10 +4,12 @@ use std::str::FromStr;
 pub enum Command {
     Help,
     Exit,
+    Info,
     Reload,
     Load(String),
     Char(String),
-    Gen,
+    Tokens,
+    Gen(usize),
     Swipe,
     Undo,
     Redo,
@@ -25,6 +27,7 @@ impl FromStr for Command {
             None => bail!("Cannot parse command from empty input"),
             Some("help") => Command::Help,
             Some("exit") => Command::Exit,
+            Some("info") => Command::Info,
             Some("load") => {
                 if let Some(name) = words.next() {
                     Command::Load(name.to_string())
@@ -57,7 +60,15 @@ impl FromStr for Command {
                     }
                 }
             },
-            Some("gen") => Command::Gen,
+            Some("gen") => match words.next() {
+                None => Command::Gen(1),
+                Some(s) => match s.parse::<usize>() {
+                    Ok(0) => bail!("\"gen\" count must be at least 1"),
+                    Ok(count) => Command::Gen(count),
+                    Err(_) => bail!("Unrecognized argument for gen: {s:?}"),
+                },
+            },
+            Some("tokens") => Command::Tokens,
             Some("regen") => Command::Swipe,
             Some("undo") => Command::Undo,
             Some("redo") => Command::Redo,
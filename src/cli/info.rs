@@ -0,0 +1,110 @@
+use super::*;
+
+const HEADER: [&str; 8] = [
+    "Pool", "Instance", "Status", "Model", "Version", "Context", "Last gen", "Gens",
+];
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+fn row(info: &InstanceInfo) -> Vec<String> {
+    let mut row = vec![info.pool.clone(), info.name.clone(), info.status.clone()];
+
+    match &info.info {
+        Ok(server) => {
+            let last_gen = match (server.last_tokens, server.last_eval_time) {
+                (Some(tokens), Some(time)) if time > 0.0 => {
+                    Some(format!("{tokens} T, {:.1} T/s", tokens as f64 / time))
+                }
+                (Some(tokens), _) => Some(format!("{tokens} T")),
+                _ => None,
+            };
+            let gens = server.total_gens.map(|gens| match server.queue {
+                Some(queue) if queue > 0 => format!("{gens} ({queue} queued)"),
+                _ => gens.to_string(),
+            });
+
+            row.extend([
+                or_dash(server.model.as_ref()),
+                or_dash(server.version.as_ref()),
+                or_dash(server.context_length),
+                or_dash(last_gen),
+                or_dash(gens),
+            ]);
+        }
+        Err(_) => row.extend(["-"; 5].map(String::from)),
+    }
+
+    row
+}
+
+/// Prints `rows` with aligned columns.
+fn print_table(rows: &[Vec<String>]) {
+    let mut widths = vec![0; rows.iter().map(Vec::len).max().unwrap_or(0)];
+
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Returns a warning for each instance whose context is smaller than `max_context_length`.
+pub(super) fn context_warnings(infos: &[InstanceInfo], max_context_length: usize) -> Vec<String> {
+    infos
+        .iter()
+        .filter_map(|info| {
+            let context_length = info.info.as_ref().ok()?.context_length?;
+
+            (context_length < max_context_length).then(|| {
+                format!(
+                    "Warning: max_context_length is {max_context_length}, but {} in pool \"{}\" \
+                    only has a context of {context_length} tokens.",
+                    info.name, info.pool
+                )
+            })
+        })
+        .collect()
+}
+
+impl Cli {
+    /// Prints what each instance reports about its model and performance.
+    pub async fn info(&self) -> Result<()> {
+        let prompt = self.get_prompt()?;
+        let Some(servers) = self.servers.as_ref() else {
+            bail!("No servers initialized!")
+        };
+
+        let infos = servers.info().await?;
+        let mut rows = vec![HEADER.map(String::from).to_vec()];
+        rows.extend(infos.iter().map(row));
+        print_table(&rows);
+
+        for info in &infos {
+            if let Err(e) = &info.info {
+                println!(
+                    "{} in pool \"{}\" failed to respond: {e}",
+                    info.name, info.pool
+                );
+            }
+        }
+
+        for warning in context_warnings(&infos, prompt.config.prompt.max_context_length) {
+            println!("{warning}");
+        }
+
+        Ok(())
+    }
+}
@@ -1,6 +1,16 @@
+mod args;
+mod budget;
+mod check;
 mod command;
 mod history;
-
+mod info;
+mod serve;
+#[cfg(test)]
+mod tests;
+
+pub use args::*;
+use budget::*;
+pub use check::*;
 use command::*;
 use history::*;
 
@@ -8,19 +18,31 @@ use crate::files::*;
 use crate::server::*;
 use anyhow::{bail, Result};
 
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::FromRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use tokio::task::{JoinError, JoinHandle};
 
 const HELP_STRING: &str = "\
 Meta:
     help - Display this help text.
     exit - Exit the program.
 
+Servers:
+    info - Show the model, version, context size and performance of each server instance.
+
 Files:
     load <filename> - Load a prompt file.
     reload/load - Reload the current prompt file.
 
 Generate:
+    tokens - Show how many tokens each part of the prompt takes, and how much of the context is left.
     gen - Reload the prompt file, generate text according to it, and write the response back to the file.
+    gen <count> - Generate <count> responses at once across all instances, write the first to the file, and record the rest as swipes.
     regen/swipe - Undo, then generate text.
 
 History:
@@ -30,6 +52,73 @@ History:
     swipe <index> - Writes the response with id <index> to the current file.
 ";
 
+async fn join_all<T>(handles: Vec<JoinHandle<T>>) -> Vec<Result<T, JoinError>> {
+    let mut out = Vec::new();
+
+    for handle in handles {
+        out.push(handle.await);
+    }
+
+    out
+}
+
+/// Resolves once the user presses Ctrl-C or `interrupt` is notified.
+async fn interrupted(interrupt: &Notify) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = interrupt.notified() => {}
+    }
+}
+
+/// Tells the user that attempt number `attempt` will be retried, and waits for the backoff in
+/// `retry`. Returns false if no attempts are left or the wait was interrupted.
+async fn wait_to_retry(retry: &RetryConfig, attempt: u32, interrupt: &Notify) -> bool {
+    let Some(delay) = retry.backoff(attempt) else {
+        return false;
+    };
+
+    println!(
+        "Retrying in {:.1}s (attempt {} of {})...",
+        delay.as_secs_f64(),
+        attempt + 1,
+        retry.max_attempts
+    );
+
+    tokio::select! {
+        _ = tokio::time::sleep(delay) => true,
+        _ = interrupted(interrupt) => false,
+    }
+}
+
+/// Sends everything printed to stdout to stderr instead, and returns a handle to the original
+/// stdout. Status messages are printed from all over, so this keeps them out of output meant for
+/// other programs.
+fn redirect_stdout() -> Result<File> {
+    std::io::stdout().flush()?;
+
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+
+        if fd < 0 || libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+/// Writes each of `responses` to `out`, ending them with a NUL character if `null` is set and a
+/// newline otherwise.
+fn write_responses(out: &mut impl Write, responses: &[String], null: bool) -> Result<()> {
+    let end = if null { "\0" } else { "\n" };
+
+    for response in responses {
+        write!(out, "{response}{end}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
 #[derive(Default)]
 pub struct Cli {
     servers: Option<Servers>,
@@ -37,6 +126,10 @@ pub struct Cli {
     prompt: Option<Prompt>,
     character: Option<String>,
     history: History,
+    /// Interrupts the running generation, like Ctrl-C.
+    interrupt: Arc<Notify>,
+    /// Where generations are previewed.
+    preview: PreviewSink,
 }
 
 impl Cli {
@@ -66,12 +159,31 @@ impl Cli {
     }
 
     pub async fn reload_file(&mut self) -> Result<()> {
-        let prompt: Prompt = parse_from_file(self.get_file()?)?;
+        let prompt = load_prompt(self.get_file()?)?;
+
+        if self.prompt.as_ref().map(|old| &old.warnings) != Some(&prompt.warnings) {
+            for warning in &prompt.warnings {
+                println!("Warning: {warning}");
+            }
+        }
+
+        let configs = prompt.server_configs()?;
+        let old_configs = match self.prompt.as_ref() {
+            Some(prompt) => Some(prompt.server_configs()?),
+            None => None,
+        };
 
-        if Some(&prompt.config.server) != self.prompt.as_ref().map(|prompt| &prompt.config.server) {
-            println!("Initializing server...");
-            std::mem::drop(self.servers.take());
-            self.servers = Some(Servers::from_config(&prompt.config.server).await?);
+        if Some(&configs) != old_configs.as_ref() {
+            match self.servers.as_mut() {
+                Some(servers) => {
+                    println!("Updating server...");
+                    servers.reconcile(&configs).await?;
+                }
+                None => {
+                    println!("Initializing server...");
+                    self.servers = Some(Servers::from_configs(&configs).await?);
+                }
+            }
             println!("Done!");
         }
 
@@ -102,7 +214,9 @@ impl Cli {
         Ok(())
     }
 
-    pub async fn generate(&mut self) -> Result<()> {
+    /// Generates a response as the current character, previewing it as it arrives. Returns
+    /// `None` if the user cancelled it.
+    async fn generate_response(&mut self) -> Result<Option<String>> {
         self.reload_file().await?;
 
         let Some(file) = self.file.as_ref() else {
@@ -118,31 +232,238 @@ impl Cli {
             bail!("No servers initialized!")
         };
 
-        let (gen, abort) = servers.generate_with_preview(
-            prompt.get_server_prompt(character)?,
-            std::time::Duration::from_millis(100),
-        );
-
         self.history.set_prompt(prompt.prompt.clone());
 
-        let mut gen = Box::pin(gen);
-
-        let mut generation = tokio::select! {
-            res = &mut gen => {
-                res?
+        let server_prompt = server_prompt_for(servers, prompt, character).await?;
+        let pool = prompt.pool(character)?;
+        let chat = file.to_string_lossy();
+        let interrupt = &self.interrupt;
+        let preview = &self.preview;
+        let mut attempt = 1;
+
+        let generation = loop {
+            let mut was_interrupted = false;
+            let res = async {
+                let (gen, abort) = servers
+                    .generate_with_preview(
+                        pool,
+                        &chat,
+                        server_prompt.clone(),
+                        std::time::Duration::from_millis(100),
+                        preview.clone(),
+                    )
+                    .await?;
+
+                let mut gen = Box::pin(gen);
+
+                tokio::select! {
+                    res = &mut gen => res.map(Some),
+                    _ = interrupted(interrupt) => {
+                        was_interrupted = true;
+                        println!("\nStopping... Press Ctrl-C again to cancel without waiting.");
+
+                        let stopped = async {
+                            if let Err(e) = abort.await {
+                                println!("Failed to stop generation: {e}");
+                            }
+                            gen.await
+                        };
+
+                        tokio::select! {
+                            res = stopped => res.map(Some),
+                            _ = interrupted(interrupt) => Ok(None),
+                        }
+                    }
+                }
             }
-            _ = tokio::signal::ctrl_c() => {
-                abort.await?;
-                gen.await?
+            .await;
+
+            match res {
+                Ok(Some(generation)) => break generation,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    println!("\nGeneration failed: {e}");
+
+                    // The user asked to stop, so the failure isn't retried.
+                    if was_interrupted
+                        || !wait_to_retry(&prompt.config.retry, attempt, interrupt).await
+                    {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
             }
         };
         println!();
 
-        generation = prompt.finalize_response(character, generation)?;
-        insert_response_into_file(file, &generation)?;
+        Ok(Some(prompt.finalize_response(character, generation)?))
+    }
+
+    /// Generates a response and writes it to the file. Returns the response, or `None` if the
+    /// user cancelled it.
+    pub async fn generate(&mut self) -> Result<Option<String>> {
+        let Some(generation) = self.generate_response().await? else {
+            println!("Generation cancelled.");
+            return Ok(None);
+        };
+
+        insert_response_into_file(self.get_file()?, &generation)?;
         self.history.add_response(&generation);
         self.reload_file().await?;
-        Ok(())
+        Ok(Some(generation))
+    }
+
+    /// Undoes the last response, then generates another.
+    pub async fn swipe(&mut self) -> Result<Option<String>> {
+        self.history.undo();
+        self.write_prompt_to_file()?;
+        self.generate().await
+    }
+
+    /// Generates `count` responses at once as the current character, spread across idle
+    /// instances. Returns the responses which succeeded, or `None` if the user cancelled them.
+    async fn generate_responses(&mut self, count: usize) -> Result<Option<Vec<String>>> {
+        self.reload_file().await?;
+
+        let Some(file) = self.file.as_ref() else {
+            bail!("No file loaded!")
+        };
+        let Some(prompt) = self.prompt.as_ref() else {
+            bail!("No file loaded!")
+        };
+        let Some(character) = self.character.as_ref() else {
+            bail!("No character selected!")
+        };
+        let Some(servers) = self.servers.as_mut() else {
+            bail!("No servers initialized!")
+        };
+
+        let server_prompt = server_prompt_for(servers, prompt, character).await?;
+        let pool = prompt.pool(character)?;
+        let chat = file.to_string_lossy();
+        let mut results: Vec<Option<String>> = vec![None; count];
+        let mut pending: Vec<usize> = (0..count).collect();
+        let mut attempt = 1;
+
+        self.history.set_prompt(prompt.prompt.clone());
+        println!("Generating {count} responses...");
+
+        loop {
+            let mut gens = Vec::new();
+            let mut aborts = Vec::new();
+            let mut failed = Vec::new();
+
+            for &i in &pending {
+                let mut server_prompt = server_prompt.clone();
+                server_prompt.sampler_seed = server_prompt.sampler_seed.wrapping_add(i as u64);
+
+                match servers.generate(pool, &chat, server_prompt).await {
+                    Ok((gen, abort)) => {
+                        gens.push((i, tokio::spawn(gen)));
+                        aborts.push(abort);
+                    }
+                    Err(e) => failed.push((i, e)),
+                }
+            }
+
+            let (indices, handles): (Vec<_>, Vec<_>) = gens.into_iter().unzip();
+            let cancels: Vec<_> = handles.iter().map(JoinHandle::abort_handle).collect();
+            let mut joined = Box::pin(join_all(handles));
+            let mut was_interrupted = false;
+
+            let joined = tokio::select! {
+                res = &mut joined => res,
+                _ = interrupted(&self.interrupt) => {
+                    was_interrupted = true;
+                    println!("Stopping... Press Ctrl-C again to cancel without waiting.");
+
+                    let stopped = async {
+                        for abort in aborts {
+                            if let Err(e) = abort.await {
+                                println!("Failed to stop generation: {e}");
+                            }
+                        }
+                        (&mut joined).await
+                    };
+
+                    tokio::select! {
+                        res = stopped => res,
+                        _ = interrupted(&self.interrupt) => {
+                            for cancel in cancels {
+                                cancel.abort();
+                            }
+                            return Ok(None);
+                        }
+                    }
+                }
+            };
+
+            for (i, res) in indices.into_iter().zip(joined) {
+                match res? {
+                    Ok(generation) => results[i] = Some(generation),
+                    Err(e) => failed.push((i, e)),
+                }
+            }
+
+            failed.sort_by_key(|(i, _)| *i);
+
+            for (i, e) in &failed {
+                println!("Generation {i} failed: {e}");
+            }
+
+            if failed.is_empty()
+                || was_interrupted
+                || !wait_to_retry(&prompt.config.retry, attempt, &self.interrupt).await
+            {
+                break;
+            }
+
+            pending = failed.into_iter().map(|(i, _)| i).collect();
+            attempt += 1;
+        }
+
+        let mut generations = Vec::new();
+
+        for generation in results.into_iter().flatten() {
+            generations.push(prompt.finalize_response(character, generation)?);
+        }
+
+        if generations.is_empty() {
+            bail!("All generations failed!")
+        }
+
+        Ok(Some(generations))
+    }
+
+    /// Generates `count` responses at once, spread across idle instances. Every response is
+    /// recorded as a swipe, and the first one is written to the file. Returns the responses, or
+    /// `None` if the user cancelled them.
+    pub async fn generate_batch(&mut self, count: usize) -> Result<Option<Vec<String>>> {
+        let Some(generations) = self.generate_responses(count).await? else {
+            println!("Generations cancelled.");
+            return Ok(None);
+        };
+
+        insert_response_into_file(self.get_file()?, &generations[0])?;
+
+        for generation in &generations {
+            self.history.add_response(generation);
+        }
+
+        self.print_responses();
+        self.reload_file().await?;
+        Ok(Some(generations))
+    }
+
+    fn print_responses(&self) {
+        for (i, response) in self.history.responses().iter().enumerate() {
+            let end = response
+                .char_indices()
+                .nth(80)
+                .map_or(response.len(), |(end, _)| end);
+
+            println!("{i}: {:?}", &response[..end]);
+        }
     }
 
     fn write_prompt_to_file(&self) -> Result<()> {
@@ -155,13 +476,18 @@ impl Cli {
             Command::Exit => return Ok(false),
             Command::Load(file) => self.load_file(file).await?,
             Command::Reload => self.reload_file().await?,
+            Command::Info => self.info().await?,
             Command::Char(char) => self.set_character(char.clone()).await?,
-            Command::Gen => self.generate().await?,
-            Command::Swipe => {
-                self.history.undo();
-                self.write_prompt_to_file()?;
+            Command::Tokens => self.tokens().await?,
+            Command::Gen(1) => {
                 self.generate().await?;
             }
+            Command::Gen(count) => {
+                self.generate_batch(count).await?;
+            }
+            Command::Swipe => {
+                self.swipe().await?;
+            }
             Command::Undo => {
                 self.history.undo();
                 self.write_prompt_to_file()?;
@@ -170,13 +496,7 @@ impl Cli {
                 self.history.redo();
                 self.write_prompt_to_file()?;
             }
-            Command::SwipeList => {
-                let responses = self.history.responses();
-
-                for (i, response) in responses.iter().enumerate() {
-                    println!("{i}: {:?}", &response[..response.len().min(80)]);
-                }
-            }
+            Command::SwipeList => self.print_responses(),
             Command::SwipeIndex(i) => {
                 self.history.with_response(i)?;
                 self.write_prompt_to_file()?;
@@ -186,6 +506,36 @@ impl Cli {
         Ok(true)
     }
 
+    /// Generates as described by `args` without starting the interactive prompt.
+    pub async fn run_gen(&mut self, args: &GenArgs) -> Result<()> {
+        let stdout = if args.stdout {
+            Some(redirect_stdout()?)
+        } else {
+            None
+        };
+
+        self.load_file(&args.file).await?;
+        self.set_character(args.character.clone()).await?;
+
+        let responses = match args.count {
+            1 => self
+                .generate_response()
+                .await?
+                .map(|response| vec![response]),
+            count => self.generate_responses(count).await?,
+        };
+        let Some(responses) = responses else {
+            bail!("Generation cancelled.")
+        };
+
+        match stdout {
+            Some(mut stdout) => write_responses(&mut stdout, &responses, args.null)?,
+            None => insert_response_into_file(self.get_file()?, &responses[0])?,
+        }
+
+        Ok(())
+    }
+
     pub async fn run(&mut self) -> Result<()> {
         let config = rustyline::config::Builder::new()
             .auto_add_history(true)
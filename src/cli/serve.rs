@@ -0,0 +1,305 @@
+//! A JSON-RPC 2.0 protocol for editor plugins, spoken over stdin and stdout with one message per
+//! line.
+//!
+//! Requests, with their params and results:
+//! - `load {"file": path}`, `reload`, `char {"name": name}`, `undo`, `redo` and
+//!   `swipe/select {"index": i}` return `null`.
+//! - `gen {"count": n}` generates `n` responses, 1 if not given, and returns
+//!   `{"responses": [...]}`. The first response is written to the file.
+//! - `swipe` undoes the last response and generates another, returning `{"responses": [...]}`.
+//! - `swipe/list` returns `{"responses": [...]}` with every response to the current prompt.
+//! - `abort` stops the running generation, keeping what was generated so far. A second `abort`
+//!   cancels it without waiting, and the `gen` request fails. An `abort` sent while no request
+//!   is being handled is ignored.
+//! - `exit` returns `null` and stops the server.
+//!
+//! Requests are handled one at a time, except for `abort`. While generating, the server sends
+//! `preview {"text": text}` notifications with each piece of generated text.
+
+use super::*;
+
+use std::future::Future;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The request failed, with the same message the interactive prompt would print.
+const COMMAND_FAILED: i64 = -32000;
+/// The user cancelled the generation before it finished.
+const CANCELLED: i64 = -32800;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    /// `None` for notifications, which get no response.
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(COMMAND_FAILED, e.to_string())
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    }
+}
+
+/// Returns the param called `name`, or `default` if it isn't given.
+fn param<T: serde::de::DeserializeOwned>(
+    params: &Value,
+    name: &str,
+    default: Option<T>,
+) -> Result<T, RpcError> {
+    match params.get(name) {
+        Some(value) => serde_json::from_value(value.clone())
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("Invalid \"{name}\": {e}"))),
+        None => default.ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing \"{name}\""))),
+    }
+}
+
+fn responses(responses: Option<Vec<String>>) -> Result<Value, RpcError> {
+    match responses {
+        Some(responses) => Ok(json!({ "responses": responses })),
+        None => Err(RpcError::new(CANCELLED, "Generation cancelled.")),
+    }
+}
+
+impl Cli {
+    async fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "load" => {
+                self.load_file(param::<String>(params, "file", None)?)
+                    .await?
+            }
+            "reload" => self.reload_file().await?,
+            "char" => self.set_character(param(params, "name", None)?).await?,
+            "gen" => {
+                return match param(params, "count", Some(1))? {
+                    0 => Err(RpcError::new(INVALID_PARAMS, "\"count\" must be positive")),
+                    1 => responses(self.generate().await?.map(|response| vec![response])),
+                    count => responses(self.generate_batch(count).await?),
+                };
+            }
+            "swipe" => return responses(self.swipe().await?.map(|response| vec![response])),
+            "swipe/list" => return responses(Some(self.history.responses().to_vec())),
+            "swipe/select" => {
+                self.history.with_response(param(params, "index", None)?)?;
+                self.write_prompt_to_file()?;
+            }
+            "undo" => {
+                self.history.undo();
+                self.write_prompt_to_file()?;
+            }
+            "redo" => {
+                self.history.redo();
+                self.write_prompt_to_file()?;
+            }
+            "exit" => {}
+            _ => {
+                return Err(RpcError::new(
+                    METHOD_NOT_FOUND,
+                    format!("Unknown method \"{method}\""),
+                ))
+            }
+        }
+
+        Ok(Value::Null)
+    }
+}
+
+/// Counts the requests which were read and finished, so that an `abort` only stops a request
+/// which was read before it, and not one which comes later.
+#[derive(Debug, Default)]
+struct Progress {
+    read: u64,
+    finished: u64,
+}
+
+/// Consumes the notification stored in `notify` by an earlier `notify_one`, if any.
+fn clear_notification(notify: &Notify) {
+    let notified = std::pin::pin!(notify.notified());
+    let _ = notified.poll(&mut std::task::Context::from_waker(std::task::Waker::noop()));
+}
+
+/// Reads requests from `lines`. `abort` is handled right away, by notifying `interrupt` if a
+/// request is still unfinished, and the rest are sent to `requests`.
+async fn read_requests(
+    mut lines: UnboundedReceiver<String>,
+    requests: UnboundedSender<Request>,
+    messages: UnboundedSender<Value>,
+    interrupt: Arc<Notify>,
+    progress: Arc<Mutex<Progress>>,
+) {
+    while let Some(line) = lines.recv().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request = serde_json::from_str::<Value>(&line)
+            .map_err(|e| RpcError::new(PARSE_ERROR, e.to_string()))
+            .and_then(|value| {
+                serde_json::from_value::<Request>(value)
+                    .map_err(|e| RpcError::new(INVALID_REQUEST, e.to_string()))
+            });
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                let _ = messages.send(response(Value::Null, Err(e)));
+                continue;
+            }
+        };
+
+        if request.method == "abort" {
+            let progress = progress.lock().unwrap();
+
+            // The notification is kept until the generation waits for it, so an abort sent
+            // before the generation gets going isn't lost.
+            if progress.finished < progress.read {
+                interrupt.notify_one();
+            }
+            drop(progress);
+
+            if let Some(id) = request.id {
+                let _ = messages.send(response(id, Ok(Value::Null)));
+            }
+        } else {
+            progress.lock().unwrap().read += 1;
+
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Writes `messages` and `previews` to `output`, one per line. Previews are written first, so
+/// that they arrive before the response to the request which generated them.
+async fn write_messages(
+    mut output: Box<dyn Write + Send>,
+    mut messages: UnboundedReceiver<Value>,
+    mut previews: UnboundedReceiver<String>,
+) -> Result<()> {
+    loop {
+        let message = tokio::select! {
+            biased;
+            Some(text) = previews.recv() => json!({
+                "jsonrpc": "2.0",
+                "method": "preview",
+                "params": { "text": text },
+            }),
+            message = messages.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+
+        serde_json::to_writer(&mut output, &message)?;
+        output.write_all(b"\n")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+impl Cli {
+    /// Handles the requests in `lines` until they end or an `exit` request, writing responses
+    /// and notifications to `output`.
+    pub async fn serve(
+        &mut self,
+        lines: UnboundedReceiver<String>,
+        output: Box<dyn Write + Send>,
+    ) -> Result<()> {
+        let (request_sender, mut requests) = mpsc::unbounded_channel();
+        let (message_sender, messages) = mpsc::unbounded_channel();
+        let (preview_sender, previews) = mpsc::unbounded_channel();
+
+        let progress = Arc::new(Mutex::new(Progress::default()));
+
+        self.preview = PreviewSink::Channel(preview_sender);
+
+        let reader = tokio::spawn(read_requests(
+            lines,
+            request_sender,
+            message_sender.clone(),
+            self.interrupt.clone(),
+            progress.clone(),
+        ));
+        let writer = tokio::spawn(write_messages(output, messages, previews));
+
+        while let Some(request) = requests.recv().await {
+            let result = self.call(&request.method, &request.params).await;
+
+            {
+                let mut progress = progress.lock().unwrap();
+                progress.finished += 1;
+                // Aborts which the request didn't wait for don't carry over to the next one.
+                clear_notification(&self.interrupt);
+            }
+
+            if let Some(id) = request.id {
+                let _ = message_sender.send(response(id, result));
+            }
+            if request.method == "exit" {
+                break;
+            }
+        }
+
+        reader.abort();
+        self.preview = PreviewSink::Stdout;
+        drop(message_sender);
+        writer.await?
+    }
+
+    /// Runs `serve` on stdin and stdout. Everything else printed to stdout goes to stderr.
+    pub async fn serve_stdio(&mut self) -> Result<()> {
+        let stdout = redirect_stdout()?;
+        let (sender, lines) = mpsc::unbounded_channel();
+
+        // Reading stdin blocks, and a blocked read in the runtime would keep it from shutting
+        // down after `exit`.
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.serve(lines, Box::new(stdout)).await
+    }
+}
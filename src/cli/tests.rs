@@ -0,0 +1,1003 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::*;
+use crate::server::mock::{MockScript, MockServer, MOCK_CONTEXT_LENGTH};
+
+const PROMPT: &str = "User: Hello!";
+
+/// A prompt file in the temp directory, deleted when dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(contents: &str) -> TempFile {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "kobold_cli_test_{}_{}.txt",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+
+    fn read(&self) -> String {
+        std::fs::read_to_string(&self.0).unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Additions to the prompt file made by `prompt_file`. Empty fields add nothing.
+#[derive(Clone, Copy, Default)]
+struct Extra<'a> {
+    /// Settings of the default server pool.
+    server: &'a str,
+    /// Other config settings.
+    config: &'a str,
+    /// Sections between the config and the character.
+    sections: &'a str,
+    /// Settings of the "Bot" character.
+    character: &'a str,
+    /// Replaces `PROMPT`.
+    prompt: &'a str,
+}
+
+/// Returns a prompt file using the servers at `urls`, with the additions in `extra`.
+fn prompt_file(urls: &[String], extra: Extra) -> String {
+    let lines = |text: &str| match text {
+        "" => String::new(),
+        text => format!("{text}\n"),
+    };
+    let prompt = match extra.prompt {
+        "" => PROMPT,
+        prompt => prompt,
+    };
+
+    format!(
+        "\
+<|CONFIG|>
+user_name: User
+server:
+  urls: [{}]
+  {}
+retry:
+  backoff: 0
+{}<|ENDCONFIG|>
+{}<|CHAR|>
+name: Bot
+prefix: \"\\nBot:\"
+stop_sequence: [\"\\n{{{{user}}}}:\"]
+{}<|ENDCHAR|>
+<|PROMPT|>
+{prompt}",
+        urls.join(", "),
+        extra.server,
+        lines(extra.config),
+        lines(extra.sections),
+        lines(extra.character),
+    )
+}
+
+/// Starts a mock server with `script`, and a CLI with a prompt file using it and "Bot" selected.
+async fn setup(script: MockScript) -> (MockServer, TempFile, Cli) {
+    setup_with(script, Extra::default()).await
+}
+
+/// Like `setup`, with the additions in `extra`.
+async fn setup_with(script: MockScript, extra: Extra<'_>) -> (MockServer, TempFile, Cli) {
+    let server = MockServer::start(script, 0).await.unwrap();
+    let (file, cli) = load(&[server.url()], extra).await;
+    (server, file, cli)
+}
+
+/// Starts a CLI with a prompt file using the servers at `urls` and "Bot" selected.
+async fn load(urls: &[String], extra: Extra<'_>) -> (TempFile, Cli) {
+    let file = TempFile::new(&prompt_file(urls, extra));
+    let mut cli = Cli::new();
+
+    cli.run_command(&format!("load {}", file.0.display()))
+        .await
+        .unwrap();
+    cli.run_command("char Bot").await.unwrap();
+
+    (file, cli)
+}
+
+fn script(responses: &[&str]) -> MockScript {
+    MockScript {
+        responses: responses.iter().map(|s| s.to_string()).collect(),
+        ..MockScript::default()
+    }
+}
+
+#[tokio::test]
+async fn gen_writes_response_to_file() {
+    let (server, file, mut cli) = setup(script(&[" Hi there.\nUser: Ignored."])).await;
+
+    cli.run_command("gen").await.unwrap();
+
+    assert_eq!(server.prompts(), [format!("{PROMPT}\nBot:")]);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi there.")));
+}
+
+#[tokio::test]
+async fn swipe_replaces_response() {
+    let (server, file, mut cli) = setup(script(&[" First.", " Second."])).await;
+
+    cli.run_command("gen").await.unwrap();
+    cli.run_command("swipe").await.unwrap();
+
+    // The swipe is generated from the prompt without the first response.
+    assert_eq!(server.prompts()[1], server.prompts()[0]);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Second.")));
+    assert_eq!(cli.history.responses(), ["\nBot: First.", "\nBot: Second."]);
+
+    cli.run_command("swipe 0").await.unwrap();
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: First.")));
+}
+
+#[tokio::test]
+async fn undo_and_redo_restore_file() {
+    let (_server, file, mut cli) = setup(script(&[" Hi."])).await;
+    let original = file.read();
+
+    cli.run_command("gen").await.unwrap();
+    let generated = file.read();
+
+    cli.run_command("undo").await.unwrap();
+    assert_eq!(file.read(), original);
+
+    cli.run_command("redo").await.unwrap();
+    assert_eq!(file.read(), generated);
+}
+
+#[tokio::test]
+async fn abort_keeps_partial_response() {
+    let response = " one two three four five six seven eight nine ten";
+    let (_server, file, mut cli) = setup(MockScript {
+        token_delay: Duration::from_millis(100),
+        ..script(&[response])
+    })
+    .await;
+
+    interrupt_after(cli.interrupt.clone(), &[350]);
+
+    cli.run_command("gen").await.unwrap();
+
+    let contents = file.read();
+    let partial = contents.rsplit_once("\nBot:").unwrap().1;
+
+    assert!(!partial.is_empty());
+    assert!(partial.len() < response.len());
+    assert!(response.starts_with(partial));
+}
+
+/// Notifies `interrupt` after each of `delays`, in milliseconds, like pressing Ctrl-C.
+fn interrupt_after(interrupt: Arc<Notify>, delays: &'static [u64]) {
+    tokio::spawn(async move {
+        for delay in delays {
+            tokio::time::sleep(Duration::from_millis(*delay)).await;
+            interrupt.notify_one();
+        }
+    });
+}
+
+#[tokio::test]
+async fn second_interrupt_cancels_stuck_generation() {
+    let (_server, file, mut cli) = setup(MockScript {
+        token_delay: Duration::from_millis(500),
+        ignore_abort: true,
+        ..script(&[" one two three four five six"])
+    })
+    .await;
+    let original = file.read();
+
+    interrupt_after(cli.interrupt.clone(), &[300, 300]);
+
+    let start = std::time::Instant::now();
+    cli.run_command("gen").await.unwrap();
+
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(file.read(), original);
+}
+
+#[tokio::test]
+async fn failure_after_interrupt_is_not_retried() {
+    let (server, file, mut cli) = setup_with(
+        MockScript {
+            token_delay: Duration::from_millis(500),
+            ignore_abort: true,
+            ..script(&[" one two three four five six"])
+        },
+        Extra {
+            server: "timeouts: { total: 1 }",
+            ..Extra::default()
+        },
+    )
+    .await;
+    let original = file.read();
+
+    interrupt_after(cli.interrupt.clone(), &[300]);
+
+    let err = cli.run_command("gen").await.unwrap_err();
+
+    assert!(err.to_string().contains("timed out"));
+    assert_eq!(server.prompts().len(), 1);
+    assert_eq!(file.read(), original);
+}
+
+#[tokio::test]
+async fn gen_times_out() {
+    let (server, file, mut cli) = setup_with(
+        MockScript {
+            token_delay: Duration::from_millis(500),
+            ..script(&[" one two three four five six"])
+        },
+        Extra {
+            server: "timeouts: { total: 1 }",
+            ..Extra::default()
+        },
+    )
+    .await;
+    let original = file.read();
+
+    let start = std::time::Instant::now();
+    let err = cli.run_command("gen").await.unwrap_err();
+
+    assert!(err.to_string().contains("timed out"));
+    // The limit applies to each attempt, and every attempt timed out.
+    assert_eq!(server.prompts().len(), 3);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(file.read(), original);
+}
+
+#[tokio::test]
+async fn streamed_gen_holds_back_stop_sequence_split_across_events() {
+    let (server, file, mut cli) = setup(MockScript {
+        stream: true,
+        token_delay: Duration::from_millis(10),
+        ..script(&[" Hi there.\nUser: Ignored."])
+    })
+    .await;
+    let (sender, mut previews) = tokio::sync::mpsc::unbounded_channel();
+    cli.preview = PreviewSink::Channel(sender);
+
+    cli.run_command("gen").await.unwrap();
+
+    // "\nUser:" arrives as "there.\n" and "User:", and none of it is previewed.
+    let mut preview = String::new();
+    while let Ok(text) = previews.try_recv() {
+        preview.push_str(&text);
+    }
+    assert_eq!(preview, " Hi there.");
+    assert_eq!(server.streamed(), 1);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi there.")));
+}
+
+#[tokio::test]
+async fn streamed_gen_times_out_when_idle() {
+    let (server, file, mut cli) = setup_with(
+        MockScript {
+            stream: true,
+            token_delay: Duration::from_millis(1500),
+            ..script(&[" one two"])
+        },
+        Extra {
+            server: "timeouts: { idle: 1 }",
+            ..Extra::default()
+        },
+    )
+    .await;
+    let original = file.read();
+
+    let err = cli.run_command("gen").await.unwrap_err();
+
+    assert!(err.to_string().contains("sent nothing for 1 seconds"));
+    assert_eq!(server.streamed(), 3);
+    assert_eq!(file.read(), original);
+}
+
+#[tokio::test]
+async fn gen_retries_failed_requests() {
+    let (server, file, mut cli) = setup(MockScript {
+        failures: 2,
+        ..script(&[" Hi."])
+    })
+    .await;
+
+    cli.run_command("gen").await.unwrap();
+
+    assert_eq!(server.prompts().len(), 3);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi.")));
+}
+
+#[tokio::test]
+async fn gen_fails_after_max_attempts() {
+    let (server, file, mut cli) = setup(MockScript {
+        failures: 3,
+        ..script(&[" Hi."])
+    })
+    .await;
+    let original = file.read();
+
+    assert!(cli.run_command("gen").await.is_err());
+    assert_eq!(server.prompts().len(), 3);
+    assert_eq!(file.read(), original);
+}
+
+#[tokio::test]
+async fn retry_switches_to_healthy_instance() {
+    let failing = MockServer::start(
+        MockScript {
+            failures: usize::MAX,
+            ..MockScript::default()
+        },
+        0,
+    )
+    .await
+    .unwrap();
+    let healthy = MockServer::start(script(&[" Hi."]), 0).await.unwrap();
+    let (file, mut cli) = load(&[failing.url(), healthy.url()], Extra::default()).await;
+
+    cli.run_command("gen").await.unwrap();
+
+    assert_eq!(failing.prompts().len(), 1);
+    assert_eq!(healthy.prompts().len(), 1);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi.")));
+}
+
+#[tokio::test]
+async fn reload_rejects_repeated_urls() {
+    let (server, file, mut cli) = setup(script(&[" Hi."])).await;
+
+    std::fs::write(
+        &file.0,
+        prompt_file(&[server.url(), server.url()], Extra::default()),
+    )
+    .unwrap();
+    let e = cli.run_command("reload").await.unwrap_err();
+    assert!(format!("{e:#}").contains("appears more than once in urls"));
+
+    // The servers are untouched, so generating works once the file is fixed.
+    std::fs::write(&file.0, prompt_file(&[server.url()], Extra::default())).unwrap();
+    cli.run_command("gen").await.unwrap();
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi.")));
+}
+
+#[tokio::test]
+async fn only_pools_used_by_characters_are_started() {
+    let server = MockServer::start(script(&[" Hi."]), 0).await.unwrap();
+    // Neither `server` nor the "unused" pool could be started, as they have no urls or executable.
+    let file = TempFile::new(&format!(
+        "\
+<|CONFIG|>
+user_name: User
+pools:
+  remote:
+    urls: [{}]
+  unused: {{}}
+retry:
+  backoff: 0
+<|ENDCONFIG|>
+<|CHAR|>
+name: Bot
+prefix: \"\\nBot:\"
+pool: remote
+<|ENDCHAR|>
+<|PROMPT|>
+{PROMPT}",
+        server.url()
+    ));
+    let mut cli = Cli::new();
+
+    cli.run_command(&format!("load {}", file.0.display()))
+        .await
+        .unwrap();
+    cli.run_command("char Bot").await.unwrap();
+    cli.run_command("gen").await.unwrap();
+
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi.")));
+    let infos = cli.servers.as_ref().unwrap().info().await.unwrap();
+    assert!(infos.iter().all(|info| info.pool == "remote"));
+}
+
+#[tokio::test]
+async fn batch_retries_failed_generations() {
+    let (server, _file, mut cli) = setup(MockScript {
+        failures: 1,
+        ..script(&[" Hi."])
+    })
+    .await;
+
+    cli.run_command("gen 2").await.unwrap();
+
+    assert_eq!(server.prompts().len(), 3);
+    assert_eq!(cli.history.responses().len(), 2);
+}
+
+#[tokio::test]
+async fn info_reports_server_details() {
+    let (server, _file, mut cli) = setup(script(&[" one two three"])).await;
+
+    cli.run_command("gen").await.unwrap();
+    cli.run_command("info").await.unwrap();
+
+    let infos = cli.servers.as_ref().unwrap().info().await.unwrap();
+    let info = infos[0].info.as_ref().unwrap();
+
+    assert_eq!(infos[0].pool, "default");
+    assert_eq!(infos[0].name, format!("Server at {}", server.url()));
+    assert_eq!(infos[0].status, "ready");
+    assert_eq!(info.model.as_deref(), Some("koboldcpp/mock"));
+    assert_eq!(info.version.as_deref(), Some("mock"));
+    assert_eq!(info.context_length, Some(MOCK_CONTEXT_LENGTH));
+    assert_eq!(info.last_tokens, Some(3));
+    assert_eq!(info.total_gens, Some(1));
+
+    assert!(info::context_warnings(&infos, MOCK_CONTEXT_LENGTH).is_empty());
+    assert_eq!(
+        info::context_warnings(&infos, MOCK_CONTEXT_LENGTH + 1).len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn gen_drops_oldest_turns_to_fit_context() {
+    let (server, file, mut cli) = setup_with(
+        script(&[" six"]),
+        Extra {
+            config: "prompt:\n  max_context_length: 10\n  max_length: 2",
+            sections: "<|CONTEXT ctx|>\nBot is helpful.\n<|ENDCONTEXT|>",
+            character: "context: ctx",
+            prompt: "User: one\nBot: two\nUser: three\nBot: four\nUser: five",
+            ..Extra::default()
+        },
+    )
+    .await;
+
+    cli.run_command("tokens").await.unwrap();
+    cli.run_command("gen").await.unwrap();
+
+    // The mock counts one token per word, leaving 8 for the prompt.
+    assert_eq!(
+        server.prompts(),
+        ["Bot is helpful.Bot: four\nUser: five\nBot:"]
+    );
+    assert!(file
+        .read()
+        .ends_with("User: one\nBot: two\nUser: three\nBot: four\nUser: five\nBot: six"));
+}
+
+#[tokio::test]
+async fn character_sampling_overrides_merge_with_prompt_settings() {
+    let (server, _file, mut cli) = setup_with(
+        script(&[" Hi."]),
+        Extra {
+            config: "\
+prompt:
+  banned_tokens: [\"badword\"]
+  logit_bias: { 1: -1.0, 2: 2.0 }
+  grammar: \"root ::= [a-z]+\"",
+            character: "\
+banned_tokens: [\"{{user}}\"]
+logit_bias: { 2: -100.0 }
+grammar: \"root ::= [A-Z]+\"",
+            ..Extra::default()
+        },
+    )
+    .await;
+
+    cli.run_command("gen").await.unwrap();
+
+    let request = &server.requests()[0];
+    assert_eq!(
+        request["banned_tokens"],
+        serde_json::json!(["User", "badword"])
+    );
+    assert_eq!(
+        request["logit_bias"],
+        serde_json::json!({ "1": -1.0, "2": -100.0 })
+    );
+    assert_eq!(request["grammar"], "root ::= [A-Z]+");
+}
+
+#[tokio::test]
+async fn grammar_file_is_read_relative_to_prompt_file() {
+    let grammar = TempFile::new("root ::= \"{}\"");
+    let grammar_file = format!(
+        "grammar_file: {}",
+        grammar.0.file_name().unwrap().to_string_lossy()
+    );
+    let (server, _file, mut cli) = setup_with(
+        script(&[" {}"]),
+        Extra {
+            character: &grammar_file,
+            ..Extra::default()
+        },
+    )
+    .await;
+
+    cli.run_command("gen").await.unwrap();
+
+    assert_eq!(server.requests()[0]["grammar"], "root ::= \"{}\"");
+}
+
+#[tokio::test]
+async fn grammar_file_is_read_relative_to_file_defining_character() {
+    let dir = std::env::temp_dir().join(format!("kobold_cli_test_{}_chars", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("grammar.gbnf"), "root ::= [a-z]+").unwrap();
+    std::fs::write(
+        dir.join("robot.txt"),
+        "<|CHAR|>\nname: Robot\ngrammar_file: grammar.gbnf\n<|ENDCHAR|>",
+    )
+    .unwrap();
+    let include = format!(
+        "<|INCLUDE {}/robot.txt|>",
+        dir.file_name().unwrap().to_string_lossy()
+    );
+    let (server, _file, mut cli) = setup_with(
+        script(&[" beep"]),
+        Extra {
+            sections: &include,
+            ..Extra::default()
+        },
+    )
+    .await;
+
+    cli.run_command("char Robot").await.unwrap();
+    let result = cli.run_command("gen").await;
+    let _ = std::fs::remove_dir_all(&dir);
+
+    result.unwrap();
+    assert_eq!(server.requests()[0]["grammar"], "root ::= [a-z]+");
+}
+
+#[tokio::test]
+async fn sampler_settings_are_checked_before_sending() {
+    let bad_order = Extra {
+        config: "prompt: { min_p: 0.05, dry_multiplier: 0.8, sampler_order: [6, 0, 1, 7] }",
+        ..Extra::default()
+    };
+    let (server, file, mut cli) = setup_with(script(&[" Hi."]), bad_order).await;
+
+    let err = cli.run_command("gen").await.unwrap_err();
+    assert!(err.to_string().contains("Unknown sampler 7"));
+    assert!(server.requests().is_empty());
+
+    let good_order = Extra {
+        config: "prompt: { min_p: 0.05, dry_multiplier: 0.8, sampler_order: [6, 0, 1] }",
+        ..bad_order
+    };
+    std::fs::write(&file.0, prompt_file(&[server.url()], good_order)).unwrap();
+    cli.run_command("gen").await.unwrap();
+
+    let request = &server.requests()[0];
+    assert_eq!(request["min_p"], 0.05);
+    assert_eq!(request["dry_multiplier"], 0.8);
+}
+
+#[test]
+fn check_accepts_valid_prompt_file() {
+    let file = TempFile::new(&prompt_file(
+        &["http://localhost:1".to_string()],
+        Extra::default(),
+    ));
+
+    assert_eq!(check_file(&file.0).unwrap(), []);
+}
+
+#[test]
+fn check_reports_prompt_file_mistakes() {
+    let contents = prompt_file(&["http://localhost:1".to_string()], Extra::default()).replace(
+        "<|ENDCONFIG|>",
+        "prompt:\n  min_p: 2\n<|ENDCONFIG|>\n<|CHAR|>\nname: Bot\ncontext: Lore\n<|ENDCHAR|>",
+    ) + "\n{{Char}}: Hi.";
+    let file = TempFile::new(&contents);
+
+    let problems: Vec<_> = check_file(&file.0)
+        .unwrap()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let path = file.0.display();
+
+    assert_eq!(
+        problems,
+        [
+            format!("{path}:9:3: min_p must be between 0 and 1, not 2!"),
+            format!("{path}:13:1: Character \"Bot\" uses context \"Lore\", which is not defined"),
+            format!("{path}:16:1: Character \"Bot\" is already defined at {path}:11:1"),
+            format!(
+                "{path}:22:1: Unknown placeholder {{{{Char}}}}, only {{{{char}}}} and \
+                {{{{user}}}} are replaced"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn check_reports_stop_sequences_and_includes() {
+    let lore = TempFile::new("Bot is helpful.");
+    let lore_name = lore.0.file_name().unwrap().to_string_lossy();
+    let file = TempFile::new(&format!(
+        "\
+<|CONFIG|>
+user_name: User
+server:
+  urls: [http://localhost:1]
+<|ENDCONFIG|>
+<|INCLUDE {lore_name}|>
+<|CHAR|>
+name: Bot
+stop_sequence: [\"\", \"\\n{{{{user}}}}:\", \"\\n{{{{user}}}}: Hi\"]
+<|ENDCHAR|>
+<|INCLUDE kobold_cli_missing_file.txt|>
+<|PROMPT|>
+Hi."
+    ));
+
+    let problems: Vec<_> = check_file(&file.0)
+        .unwrap()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let path = file.0.display();
+    let dir = std::env::temp_dir();
+
+    assert_eq!(
+        problems,
+        [
+            format!(
+                "{path}:6:1: {} is included outside of any section or used context, so it is \
+                never used",
+                lore.0.display()
+            ),
+            format!(
+                "{path}:9:1: Character \"Bot\" has an empty stop sequence, which matches every \
+                response, so its other stop sequences are never stripped"
+            ),
+            format!(
+                "{path}:9:1: Stop sequence \"\\n{{{{user}}}}: Hi\" of \"Bot\" can never match, as \
+                it contains \"\\n{{{{user}}}}:\", which stops generation first"
+            ),
+            format!(
+                "{path}:11:1: Included file {} does not exist",
+                dir.join("kobold_cli_missing_file.txt").display()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn check_reports_unreadable_grammar_files() {
+    let file = TempFile::new(&prompt_file(
+        &["http://localhost:1".to_string()],
+        Extra {
+            character: "grammar_file: kobold_cli_missing_grammar.gbnf",
+            ..Extra::default()
+        },
+    ));
+
+    let problems = check_file(&file.0).unwrap();
+
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].location.file.as_ref(), Some(&file.0));
+    assert!(problems[0]
+        .message
+        .starts_with("Could not read grammar file"));
+}
+
+#[test]
+fn command_line_is_parsed() {
+    let args = |args: &[&str]| Args::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+
+    assert_eq!(args(&[]).unwrap(), Args::Repl);
+    assert_eq!(
+        args(&["gen", "chat.txt", "--char", "Bot Name", "--n=3", "--stdout", "--null"]).unwrap(),
+        Args::Gen(GenArgs {
+            file: "chat.txt".to_string(),
+            character: "Bot Name".to_string(),
+            count: 3,
+            stdout: true,
+            null: true,
+        })
+    );
+
+    for (bad, error) in [
+        (
+            &["gen", "chat.txt"][..],
+            "gen requires a character, given with --char",
+        ),
+        (&["gen", "--char", "Bot"], "gen requires a prompt file"),
+        (&["gen", "chat.txt", "--char"], "--char requires a value"),
+        (
+            &["gen", "chat.txt", "--char", "Bot", "--n", "0"],
+            "--n must be a positive number, not \"0\"",
+        ),
+        (
+            &["gen", "chat.txt", "--chars", "Bot"],
+            "Unknown option --chars",
+        ),
+        (
+            &["gen", "chat.txt", "--char", "Bot", "--null"],
+            "--null requires --stdout",
+        ),
+        (&["chat.txt"], "Unknown command \"chat.txt\""),
+    ] {
+        assert_eq!(args(bad).unwrap_err().to_string(), error);
+    }
+}
+
+#[tokio::test]
+async fn one_shot_gen_writes_response() {
+    let server = MockServer::start(script(&[" Hi."]), 0).await.unwrap();
+    let file = TempFile::new(&prompt_file(&[server.url()], Extra::default()));
+    let args = GenArgs {
+        file: file.0.display().to_string(),
+        character: "Bot".to_string(),
+        count: 1,
+        stdout: false,
+        null: false,
+    };
+
+    Cli::new().run_gen(&args).await.unwrap();
+
+    assert_eq!(server.prompts().len(), 1);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi.")));
+}
+
+#[tokio::test]
+async fn one_shot_gen_writes_first_of_several_responses() {
+    // One server for each generation, since the mock server can only run one at a time.
+    let servers = [
+        MockServer::start(script(&[" First."]), 0).await.unwrap(),
+        MockServer::start(script(&[" First."]), 0).await.unwrap(),
+        MockServer::start(script(&[" First."]), 0).await.unwrap(),
+    ];
+    let file = TempFile::new(&prompt_file(
+        &servers.each_ref().map(MockServer::url),
+        Extra::default(),
+    ));
+    let args = Args::parse(&[
+        "gen".to_string(),
+        file.0.display().to_string(),
+        "--char".to_string(),
+        "Bot".to_string(),
+        "--n".to_string(),
+        "3".to_string(),
+    ])
+    .unwrap();
+    let Args::Gen(args) = args else {
+        panic!("Expected gen, not {args:?}");
+    };
+
+    Cli::new().run_gen(&args).await.unwrap();
+
+    assert_eq!(servers.map(|server| server.prompts().len()), [1, 1, 1]);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: First.")));
+    assert!(!file.read().contains("First. First."));
+}
+
+#[test]
+fn printed_responses_end_with_newlines_or_nul() {
+    let responses = ["\nBot: One.".to_string(), "\nBot: Two.".to_string()];
+    let mut out = Vec::new();
+
+    write_responses(&mut out, &responses[..1], false).unwrap();
+    write_responses(&mut out, &responses, true).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "\nBot: One.\n\nBot: One.\0\nBot: Two.\0"
+    );
+}
+
+/// Output which can be read after it has been written.
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serves `requests`, sending each after the delay in milliseconds before it, and returns the
+/// messages sent back.
+async fn serve(requests: Vec<(u64, serde_json::Value)>) -> Vec<serde_json::Value> {
+    let (sender, lines) = tokio::sync::mpsc::unbounded_channel();
+    let output = SharedOutput::default();
+
+    tokio::spawn(async move {
+        for (delay, request) in requests {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            sender.send(request.to_string()).unwrap();
+        }
+        // Keep the input open, like an editor would, so only `exit` stops the server.
+        std::future::pending::<()>().await;
+    });
+
+    Cli::new()
+        .serve(lines, Box::new(output.clone()))
+        .await
+        .unwrap();
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn serve_generates_with_previews() {
+    use serde_json::json;
+
+    let server = MockServer::start(script(&[" Hi there."]), 0).await.unwrap();
+    let file = TempFile::new(&prompt_file(&[server.url()], Extra::default()));
+
+    let messages = serve(vec![
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "load", "params": { "file": file.0 } }),
+        ),
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "char", "params": { "name": "Bot" } }),
+        ),
+        (0, json!({ "jsonrpc": "2.0", "id": 3, "method": "gen" })),
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "id": 4, "method": "swipe/list" }),
+        ),
+        (0, json!({ "jsonrpc": "2.0", "id": 5, "method": "char" })),
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "id": 6, "method": "frobnicate" }),
+        ),
+        (0, json!({ "jsonrpc": "2.0", "id": 7, "method": "exit" })),
+    ])
+    .await;
+
+    let preview: String = messages
+        .iter()
+        .filter(|message| message["method"] == "preview")
+        .map(|message| message["params"]["text"].as_str().unwrap())
+        .collect();
+    let responses: Vec<_> = messages
+        .iter()
+        .filter(|message| message["method"] != "preview")
+        .cloned()
+        .collect();
+
+    assert_eq!(preview, " Hi there.");
+    assert_eq!(
+        responses,
+        [
+            json!({ "jsonrpc": "2.0", "id": 1, "result": null }),
+            json!({ "jsonrpc": "2.0", "id": 2, "result": null }),
+            json!({ "jsonrpc": "2.0", "id": 3, "result": { "responses": ["\nBot: Hi there."] } }),
+            json!({ "jsonrpc": "2.0", "id": 4, "result": { "responses": ["\nBot: Hi there."] } }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 5,
+                "error": { "code": -32602, "message": "Missing \"name\"" },
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 6,
+                "error": { "code": -32601, "message": "Unknown method \"frobnicate\"" },
+            }),
+            json!({ "jsonrpc": "2.0", "id": 7, "result": null }),
+        ]
+    );
+    // Previews of the generation arrive before its response.
+    let last_preview = messages.iter().rposition(|m| m["method"] == "preview");
+    let gen_response = messages.iter().position(|m| m["id"] == 3);
+    assert!(last_preview < gen_response);
+    assert!(file.read().ends_with(&format!("{PROMPT}\nBot: Hi there.")));
+}
+
+#[tokio::test]
+async fn serve_aborts_generation() {
+    use serde_json::json;
+
+    let response = " one two three four five six seven eight nine ten";
+    let server = MockServer::start(
+        MockScript {
+            token_delay: Duration::from_millis(100),
+            ..script(&[response])
+        },
+        0,
+    )
+    .await
+    .unwrap();
+    let file = TempFile::new(&prompt_file(&[server.url()], Extra::default()));
+
+    let messages = serve(vec![
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "method": "load", "params": { "file": file.0 } }),
+        ),
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "method": "char", "params": { "name": "Bot" } }),
+        ),
+        (0, json!({ "jsonrpc": "2.0", "id": 1, "method": "gen" })),
+        (350, json!({ "jsonrpc": "2.0", "id": 2, "method": "abort" })),
+        (0, json!({ "jsonrpc": "2.0", "id": 3, "method": "exit" })),
+    ])
+    .await;
+
+    let ids: Vec<_> = messages
+        .iter()
+        .filter(|message| message["method"] != "preview")
+        .map(|message| message["id"].clone())
+        .collect();
+    assert_eq!(ids, [json!(2), json!(1), json!(3)]);
+
+    let gen = messages.iter().find(|message| message["id"] == 1).unwrap();
+    let partial = gen["result"]["responses"][0].as_str().unwrap();
+    let partial = partial.strip_prefix("\nBot:").unwrap();
+    assert!(!partial.is_empty());
+    assert!(partial.len() < response.len());
+    assert!(response.starts_with(partial));
+}
+
+#[tokio::test]
+async fn serve_aborts_generation_which_has_not_started() {
+    use serde_json::json;
+
+    let response = " one two three four five";
+    let server = MockServer::start(
+        MockScript {
+            token_delay: Duration::from_millis(100),
+            stream: true,
+            ..script(&[response])
+        },
+        0,
+    )
+    .await
+    .unwrap();
+    let file = TempFile::new(&prompt_file(&[server.url()], Extra::default()));
+
+    let messages = serve(vec![
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "method": "load", "params": { "file": file.0 } }),
+        ),
+        (
+            0,
+            json!({ "jsonrpc": "2.0", "method": "char", "params": { "name": "Bot" } }),
+        ),
+        // Sent before any generation, so it doesn't stop the next one.
+        (0, json!({ "jsonrpc": "2.0", "method": "abort" })),
+        (0, json!({ "jsonrpc": "2.0", "id": 1, "method": "gen" })),
+        // The abort arrives while the second generation is still being routed.
+        (1000, json!({ "jsonrpc": "2.0", "id": 2, "method": "gen" })),
+        (0, json!({ "jsonrpc": "2.0", "method": "abort" })),
+        (0, json!({ "jsonrpc": "2.0", "id": 3, "method": "exit" })),
+    ])
+    .await;
+
+    let text = |id: i64| {
+        let gen = messages.iter().find(|message| message["id"] == id).unwrap();
+        let text = gen["result"]["responses"][0].as_str().unwrap();
+        text.strip_prefix("\nBot:").unwrap().to_string()
+    };
+
+    assert_eq!(text(1), response);
+    assert!(text(2).len() < response.len());
+}
@@ -1,12 +1,16 @@
 mod parse;
 mod preprocess;
+mod source;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub use parse::*;
 pub use preprocess::*;
+pub use source::*;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -18,6 +22,16 @@ pub struct Character {
     pub(crate) suffix: String,
     pub(crate) stop_sequence: Vec<String>,
     pub(crate) context: Option<String>,
+    /// The server pool this character generates on. Defaults to the pool in `server`.
+    pub(crate) pool: Option<String>,
+    /// Banned in addition to the `banned_tokens` of the prompt settings.
+    pub(crate) banned_tokens: Vec<String>,
+    /// Merged into the `logit_bias` of the prompt settings, replacing biases of the same tokens.
+    pub(crate) logit_bias: BTreeMap<u32, f64>,
+    /// Replaces the `grammar` of the prompt settings.
+    pub(crate) grammar: Option<String>,
+    /// A file containing the character's grammar, relative to the file defining the character.
+    pub(crate) grammar_file: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -41,6 +55,29 @@ pub struct ServerPrompt {
     pub(crate) top_k: usize,
     pub(crate) top_p: f64,
     pub(crate) typical: f64,
+    pub(crate) min_p: f64,
+    /// 0 to disable mirostat, or 1 or 2 for its version.
+    pub(crate) mirostat: u8,
+    pub(crate) mirostat_tau: f64,
+    pub(crate) mirostat_eta: f64,
+    /// Dynamic temperature varies the temperature by up to this much in each direction.
+    pub(crate) dynatemp_range: f64,
+    pub(crate) dynatemp_exponent: f64,
+    /// 0 to disable DRY.
+    pub(crate) dry_multiplier: f64,
+    pub(crate) dry_base: f64,
+    pub(crate) dry_allowed_length: usize,
+    pub(crate) dry_penalty_last_n: usize,
+    pub(crate) dry_sequence_breakers: Vec<String>,
+    pub(crate) xtc_threshold: f64,
+    /// 0 to disable XTC.
+    pub(crate) xtc_probability: f64,
+    /// Strings which are never generated. `{{char}}` and `{{user}}` are replaced.
+    pub(crate) banned_tokens: Vec<String>,
+    /// Biases added to the logits of token ids.
+    pub(crate) logit_bias: BTreeMap<u32, f64>,
+    /// A GBNF grammar which responses must follow, or empty for none.
+    pub(crate) grammar: String,
 }
 
 impl Default for ServerPrompt {
@@ -65,6 +102,164 @@ impl Default for ServerPrompt {
             top_k: 0,
             top_p: 0.92,
             typical: 1.0,
+            min_p: 0.0,
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            dynatemp_range: 0.0,
+            dynatemp_exponent: 1.0,
+            dry_multiplier: 0.0,
+            dry_base: 1.75,
+            dry_allowed_length: 2,
+            dry_penalty_last_n: 360,
+            dry_sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+            xtc_threshold: 0.2,
+            xtc_probability: 0.0,
+            banned_tokens: Vec::new(),
+            logit_bias: BTreeMap::new(),
+            grammar: String::new(),
+        }
+    }
+}
+
+impl ServerPrompt {
+    /// Checks that the sampler settings are in range.
+    pub fn validate(&self) -> Result<()> {
+        if self.mirostat > 2 {
+            bail!("mirostat must be 0, 1 or 2, not {}!", self.mirostat);
+        }
+
+        let probabilities = [
+            ("min_p", self.min_p),
+            ("top_p", self.top_p),
+            ("tfs", self.tfs),
+            ("typical", self.typical),
+            ("xtc_threshold", self.xtc_threshold),
+            ("xtc_probability", self.xtc_probability),
+        ];
+
+        for (name, p) in probabilities {
+            if !(0.0..=1.0).contains(&p) {
+                bail!("{name} must be between 0 and 1, not {p}!");
+            }
+        }
+
+        let non_negative = [
+            ("temperature", self.temperature),
+            ("top_a", self.top_a),
+            ("dynatemp_range", self.dynatemp_range),
+            ("dry_multiplier", self.dry_multiplier),
+        ];
+
+        for (name, value) in non_negative {
+            if value < 0.0 {
+                bail!("{name} can't be negative, not {value}!");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoutingConfig {
+    /// Cost of erasing one token (or byte) from an instance's cache, relative to processing one.
+    pub(crate) erase_cost: f64,
+    /// Seconds after which the erase cost of an instance's last prompt is halved.
+    pub(crate) erase_half_life: f64,
+    /// Cost added for each request an instance is already processing.
+    pub(crate) busy_cost: f64,
+    /// Cost of moving a chat off of its home instance, or onto another chat's home instance.
+    /// Decays along with the erase cost.
+    pub(crate) home_cost: f64,
+}
+
+impl RoutingConfig {
+    pub fn validate(&self) -> Result<()> {
+        let costs = [
+            ("erase_cost", self.erase_cost),
+            ("busy_cost", self.busy_cost),
+            ("home_cost", self.home_cost),
+        ];
+
+        for (name, cost) in costs {
+            if !(cost >= 0.0 && cost.is_finite()) {
+                bail!("routing.{name} must be a non-negative number, not {cost}!");
+            }
+        }
+
+        if self.erase_half_life.is_nan() || self.erase_half_life <= 0.0 {
+            bail!(
+                "routing.erase_half_life must be positive, not {}!",
+                self.erase_half_life
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            erase_cost: 0.3,
+            erase_half_life: 600.0,
+            busy_cost: 2000.0,
+            home_cost: 500.0,
+        }
+    }
+}
+
+/// The HTTP api spoken by the servers in a `ServerConfig`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Kobold,
+    /// An OpenAI-compatible `/v1/completions` endpoint.
+    OpenAi,
+    /// The `/completion` endpoint of llama.cpp's server.
+    LlamaCpp,
+}
+
+/// Limits on how long requests to a server may take, in seconds. 0 disables a limit.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Time to wait for a connection to the server.
+    pub(crate) connect: u64,
+    /// Time to wait for the next token of a streamed generation. This includes processing the
+    /// prompt, and doesn't apply to servers which can't stream.
+    pub(crate) idle: u64,
+    /// Time one attempt at a generation may take in total. Each retry, see `RetryConfig`, gets
+    /// the full time again.
+    pub(crate) total: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect: 10,
+            idle: 300,
+            total: 0,
+        }
+    }
+}
+
+/// Linear rope scaling, passed to KoboldCpp as `--ropeconfig <scale> <base>`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RopeScaling {
+    pub(crate) scale: f64,
+    pub(crate) base: f64,
+}
+
+impl Default for RopeScaling {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            base: 10000.0,
         }
     }
 }
@@ -78,9 +273,36 @@ pub struct ServerConfig {
     pub(crate) threads: usize,
     pub(crate) blasthreads: Option<usize>,
     pub(crate) blas_batch_size: isize,
+    pub(crate) gpu_layers: Option<u32>,
+    pub(crate) rope_scaling: Option<RopeScaling>,
+    pub(crate) flash_attention: bool,
+    pub(crate) smart_context: bool,
+    pub(crate) lora: Option<String>,
+    pub(crate) mmproj: Option<String>,
+    /// Quantization of the KV cache: 0 for f16, 1 for q8 and 2 for q4. Requires flash attention.
+    pub(crate) quant_kv: Option<u8>,
     pub(crate) instances: usize,
-    pub(crate) port: u16,
+    /// The port of the first instance, with the others on the following ports. When unset,
+    /// free ports are picked automatically.
+    pub(crate) port: Option<u16>,
     pub(crate) custom_args: Vec<String>,
+    /// Seconds to wait for spawned instances to come online, or 0 to wait forever.
+    pub(crate) startup_timeout: u64,
+    /// Directory for the output of spawned instances. Defaults to `$XDG_STATE_HOME/kobold_cli`,
+    /// or `~/.local/state/kobold_cli`.
+    pub(crate) log_dir: Option<String>,
+    /// Restart instances with changed settings one at a time in the background, so that the
+    /// others keep serving, instead of waiting for all of them to restart.
+    pub(crate) rolling_restart: bool,
+    /// Urls of already running servers to use instead of spawning instances. When this is
+    /// non-empty, all of the process settings above are ignored.
+    pub(crate) urls: Vec<String>,
+    /// Only KoboldCpp servers can be spawned; other backends require `urls`.
+    pub(crate) backend: BackendKind,
+    /// The model name to request from OpenAI-compatible servers.
+    pub(crate) api_model: Option<String>,
+    pub(crate) routing: RoutingConfig,
+    pub(crate) timeouts: TimeoutConfig,
 }
 
 impl Default for ServerConfig {
@@ -92,19 +314,174 @@ impl Default for ServerConfig {
             threads: 1,
             blasthreads: None,
             blas_batch_size: 512,
+            gpu_layers: None,
+            rope_scaling: None,
+            flash_attention: false,
+            smart_context: false,
+            lora: None,
+            mmproj: None,
+            quant_kv: None,
             instances: 1,
-            port: 5001,
+            port: None,
             custom_args: Vec::new(),
+            startup_timeout: 300,
+            log_dir: None,
+            rolling_restart: false,
+            urls: Vec::new(),
+            backend: BackendKind::default(),
+            api_model: None,
+            routing: RoutingConfig::default(),
+            timeouts: TimeoutConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Resolves a path the same way KoboldCpp will, which runs in the executable's directory.
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        Path::new(&self.executable_file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(path)
+    }
+
+    /// Returns the ports of the spawned instances, if `port` is set. `validate` checks that they
+    /// fit in a `u16`.
+    pub(crate) fn ports(&self) -> Option<Vec<u16>> {
+        let port = self.port?;
+        Some((0..self.instances).map(|i| port + i as u16).collect())
+    }
+
+    /// Checks the settings used to spawn KoboldCpp. Settings are checked even if they have no
+    /// effect on this machine, e.g. GPU layers without a GPU.
+    pub fn validate(&self) -> Result<()> {
+        if !Path::new(&self.executable_file).is_file() {
+            bail!("Executable file {:?} does not exist!", self.executable_file);
+        }
+
+        let files = [
+            ("Model", Some(&self.model_file)),
+            ("Lora", self.lora.as_ref()),
+            ("Mmproj", self.mmproj.as_ref()),
+        ];
+
+        for (name, file) in files {
+            if let Some(file) = file {
+                if !self.resolve_path(file).is_file() {
+                    bail!("{name} file {file:?} does not exist!");
+                }
+            }
+        }
+
+        if self.context_size == 0 {
+            bail!("context_size must be positive!");
+        }
+
+        if self.threads == 0 {
+            bail!("threads must be positive!");
+        }
+
+        if self.instances == 0 {
+            bail!("instances must be positive!");
+        }
+
+        if let Some(port) = self.port {
+            if port as usize + self.instances > 65536 {
+                bail!(
+                    "{} instances starting at port {port} don't fit below port 65536!",
+                    self.instances
+                );
+            }
+        }
+
+        if let Some(rope) = &self.rope_scaling {
+            if !(rope.scale > 0.0 && rope.base > 0.0) {
+                bail!("rope_scaling scale and base must be positive!");
+            }
+        }
+
+        match self.quant_kv {
+            Some(quant_kv @ 3..) => bail!("quant_kv must be 0, 1 or 2, not {quant_kv}!"),
+            Some(1..) if !self.flash_attention => {
+                bail!("quant_kv requires flash_attention to be enabled!")
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// How failed generate requests are retried.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Attempts per generation, including the first.
+    pub(crate) max_attempts: u32,
+    /// Seconds to wait before the first retry.
+    pub(crate) backoff: f64,
+    /// Factor the wait is multiplied by after each retry.
+    pub(crate) backoff_factor: f64,
+    /// The longest wait between two attempts, in seconds.
+    pub(crate) max_backoff: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: 1.0,
+            backoff_factor: 2.0,
+            max_backoff: 10.0,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns how long to wait after attempt number `attempt` failed, or `None` if it was the
+    /// last one.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
         }
+
+        let secs = self.backoff * self.backoff_factor.powi(attempt as i32 - 1);
+        Some(Duration::try_from_secs_f64(secs.min(self.max_backoff)).unwrap_or_default())
     }
 }
 
+/// The name of the server pool configured by `server`.
+pub const DEFAULT_POOL: &str = "default";
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Config {
     pub(crate) user_name: String,
     pub(crate) prompt: ServerPrompt,
     pub(crate) server: ServerConfig,
+    /// Additional server pools, by name.
+    pub(crate) pools: BTreeMap<String, ServerConfig>,
+    pub(crate) retry: RetryConfig,
+}
+
+impl Config {
+    /// Returns every server pool by name, including the default pool.
+    pub fn server_configs(&self) -> Result<BTreeMap<String, ServerConfig>> {
+        if self.pools.contains_key(DEFAULT_POOL) {
+            bail!("\"{DEFAULT_POOL}\" is reserved for the pool in \"server\"!");
+        }
+
+        let mut out = self.pools.clone();
+        out.insert(DEFAULT_POOL.to_string(), self.server.clone());
+
+        for (name, config) in &out {
+            if let Err(e) = config.routing.validate() {
+                bail!("In pool \"{name}\": {e}");
+            }
+        }
+
+        Ok(out)
+    }
 }
 
 #[derive(Clone, Default, Debug)]
@@ -113,6 +490,55 @@ pub struct Prompt {
     pub(crate) characters: Vec<Character>,
     pub(crate) contexts: HashMap<String, String>,
     pub(crate) prompt: String,
+    /// Where each part of the prompt was defined.
+    pub(crate) source: SourceMap,
+    /// Problems found while parsing which don't prevent using the prompt.
+    pub(crate) warnings: Vec<Diagnostic>,
+}
+
+/// The prompt sent to the server, split into the parts which can and can't be trimmed to fit
+/// the context window.
+#[derive(Clone, Debug, Default)]
+pub struct PromptParts {
+    /// The character's context, which is always kept.
+    pub(crate) context: String,
+    /// The chat so far, whose oldest lines can be dropped.
+    pub(crate) chat: String,
+    /// The character's prefix, which is always kept.
+    pub(crate) prefix: String,
+}
+
+impl PromptParts {
+    /// Joins the parts, leaving out the first `cut` bytes of the chat.
+    pub fn join(&self, cut: usize) -> String {
+        format!("{}{}{}", self.context, &self.chat[cut..], self.prefix)
+    }
+
+    /// Returns the byte offsets at which the chat can be cut, from oldest to newest: the start
+    /// of each line except the first, and the end. Offsets where a turn of one of `speakers`
+    /// starts are also returned separately.
+    pub fn cut_points(&self, speakers: &[&str]) -> (Vec<usize>, Vec<usize>) {
+        let lines: Vec<usize> = self
+            .chat
+            .match_indices('\n')
+            .map(|(i, _)| i + 1)
+            .chain([self.chat.len()])
+            .collect();
+
+        let turns = lines
+            .iter()
+            .copied()
+            .filter(|&i| {
+                let line = &self.chat[i..];
+                i == self.chat.len()
+                    || speakers
+                        .iter()
+                        .any(|name| line.strip_prefix(name).is_some_and(|s| s.starts_with(':')))
+            })
+            .collect();
+
+        (lines, turns)
+    }
 }
 
 // May need to make this more efficient in the future
@@ -121,10 +547,33 @@ fn replace_char_user(s: &str, char: &str, user: &str) -> String {
 }
 
 impl Prompt {
+    /// Returns the server pools which some character generates on, by name. Pools which no
+    /// character uses, including the one in `server`, aren't started.
+    pub fn server_configs(&self) -> Result<BTreeMap<String, ServerConfig>> {
+        let mut configs = self.config.server_configs()?;
+
+        for char in &self.characters {
+            let pool = char.pool.as_deref().unwrap_or(DEFAULT_POOL);
+
+            if !configs.contains_key(pool) {
+                bail!(
+                    "Character {} uses pool \"{pool}\", which is not defined!",
+                    char.name
+                );
+            }
+        }
+
+        configs.retain(|name, _| {
+            self.characters
+                .iter()
+                .any(|char| char.pool.as_deref().unwrap_or(DEFAULT_POOL) == name)
+        });
+        Ok(configs)
+    }
+
     // This can be made more efficient
     fn stop_sequences(&self, character: &str) -> Result<Vec<String>> {
-        let mut names: Vec<&str> = self.characters.iter().map(|char| &char.name[..]).collect();
-        names.push(&self.config.user_name);
+        let names = self.speakers();
 
         Ok(self
             .get_character(character)?
@@ -147,11 +596,25 @@ impl Prompt {
             .ok_or_else(|| anyhow!("No character with name {character}!"))
     }
 
-    pub fn get_server_prompt(&self, char: &str) -> Result<ServerPrompt> {
-        let mut out = self.config.prompt.clone();
-        let character = self.get_character(char)?;
+    /// Returns the name of the server pool `char` generates on.
+    pub fn pool(&self, char: &str) -> Result<&str> {
+        Ok(self
+            .get_character(char)?
+            .pool
+            .as_deref()
+            .unwrap_or(DEFAULT_POOL))
+    }
 
-        out.stop_sequence = self.stop_sequences(char)?;
+    /// Returns the names of everyone who speaks in the chat.
+    pub fn speakers(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.characters.iter().map(|char| &char.name[..]).collect();
+        names.push(&self.config.user_name);
+        names
+    }
+
+    /// Returns the parts of the prompt sent to generate as `char`.
+    pub fn prompt_parts(&self, char: &str) -> Result<PromptParts> {
+        let character = self.get_character(char)?;
 
         let context = if let Some(ctx) = character.context.as_ref() {
             self.contexts
@@ -161,11 +624,40 @@ impl Prompt {
             ""
         };
 
-        out.prompt = context.to_string();
-        out.prompt.push_str(&self.prompt);
-        out.prompt.push_str(&character.temporary_prefix);
-        out.prompt.push_str(&character.prefix);
-        out.prompt = replace_char_user(&out.prompt, char, &self.config.user_name);
+        let replace = |s: &str| replace_char_user(s, char, &self.config.user_name);
+
+        Ok(PromptParts {
+            context: replace(context),
+            chat: replace(&self.prompt),
+            prefix: replace(&format!(
+                "{}{}",
+                character.temporary_prefix, character.prefix
+            )),
+        })
+    }
+
+    pub fn get_server_prompt(&self, char: &str) -> Result<ServerPrompt> {
+        let mut out = self.config.prompt.clone();
+
+        out.stop_sequence = self.stop_sequences(char)?;
+        out.prompt = self.prompt_parts(char)?.join(0);
+
+        let character = self.get_character(char)?;
+
+        out.banned_tokens
+            .extend(character.banned_tokens.iter().cloned());
+        out.banned_tokens = out
+            .banned_tokens
+            .iter()
+            .map(|token| replace_char_user(token, char, &self.config.user_name))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        out.logit_bias.extend(&character.logit_bias);
+
+        if let Some(grammar) = &character.grammar {
+            out.grammar = grammar.clone();
+        }
 
         Ok(out)
     }
@@ -188,3 +680,194 @@ impl Prompt {
         Ok(replace_char_user(&response, char, &self.config.user_name))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns settings which pass validation, using the test binary for every file.
+    fn valid_config() -> ServerConfig {
+        let exe = std::env::current_exe().unwrap().display().to_string();
+
+        ServerConfig {
+            executable_file: exe.clone(),
+            model_file: exe,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn validate_error(config: ServerConfig) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_config_passes() {
+        valid_config().validate().unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_rejected() {
+        let missing = "/kobold_cli/missing".to_string();
+
+        assert!(validate_error(ServerConfig {
+            executable_file: missing.clone(),
+            ..valid_config()
+        })
+        .starts_with("Executable file"));
+
+        let files = [
+            (
+                "Model",
+                ServerConfig {
+                    model_file: missing.clone(),
+                    ..valid_config()
+                },
+            ),
+            (
+                "Lora",
+                ServerConfig {
+                    lora: Some(missing.clone()),
+                    ..valid_config()
+                },
+            ),
+            (
+                "Mmproj",
+                ServerConfig {
+                    mmproj: Some(missing.clone()),
+                    ..valid_config()
+                },
+            ),
+        ];
+
+        for (name, config) in files {
+            assert_eq!(
+                validate_error(config),
+                format!("{name} file {missing:?} does not exist!")
+            );
+        }
+    }
+
+    #[test]
+    fn zero_values_are_rejected() {
+        let configs = [
+            (
+                "context_size",
+                ServerConfig {
+                    context_size: 0,
+                    ..valid_config()
+                },
+            ),
+            (
+                "threads",
+                ServerConfig {
+                    threads: 0,
+                    ..valid_config()
+                },
+            ),
+            (
+                "instances",
+                ServerConfig {
+                    instances: 0,
+                    ..valid_config()
+                },
+            ),
+        ];
+
+        for (name, config) in configs {
+            assert_eq!(validate_error(config), format!("{name} must be positive!"));
+        }
+    }
+
+    #[test]
+    fn ports_must_fit() {
+        let config = ServerConfig {
+            port: Some(65534),
+            instances: 2,
+            ..valid_config()
+        };
+        assert_eq!(config.ports(), Some(vec![65534, 65535]));
+        config.validate().unwrap();
+
+        assert!(validate_error(ServerConfig {
+            instances: 3,
+            ..config
+        })
+        .contains("don't fit below port 65536"));
+    }
+
+    #[test]
+    fn bad_rope_scaling_is_rejected() {
+        for (scale, base) in [(0.0, 10000.0), (1.0, -1.0), (f64::NAN, 10000.0)] {
+            assert_eq!(
+                validate_error(ServerConfig {
+                    rope_scaling: Some(RopeScaling { scale, base }),
+                    ..valid_config()
+                }),
+                "rope_scaling scale and base must be positive!"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_routing_is_rejected() {
+        RoutingConfig::default().validate().unwrap();
+
+        let configs = [
+            RoutingConfig {
+                erase_cost: f64::NAN,
+                ..RoutingConfig::default()
+            },
+            RoutingConfig {
+                busy_cost: -1.0,
+                ..RoutingConfig::default()
+            },
+            RoutingConfig {
+                home_cost: f64::INFINITY,
+                ..RoutingConfig::default()
+            },
+            RoutingConfig {
+                erase_half_life: 0.0,
+                ..RoutingConfig::default()
+            },
+            RoutingConfig {
+                erase_half_life: -1.0,
+                ..RoutingConfig::default()
+            },
+            RoutingConfig {
+                erase_half_life: f64::NAN,
+                ..RoutingConfig::default()
+            },
+        ];
+
+        for config in configs {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn quant_kv_is_checked() {
+        assert_eq!(
+            validate_error(ServerConfig {
+                quant_kv: Some(3),
+                flash_attention: true,
+                ..valid_config()
+            }),
+            "quant_kv must be 0, 1 or 2, not 3!"
+        );
+        assert_eq!(
+            validate_error(ServerConfig {
+                quant_kv: Some(1),
+                ..valid_config()
+            }),
+            "quant_kv requires flash_attention to be enabled!"
+        );
+
+        ServerConfig {
+            quant_kv: Some(1),
+            flash_attention: true,
+            ..valid_config()
+        }
+        .validate()
+        .unwrap();
+    }
+}
@@ -1,94 +1,287 @@
 use super::*;
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use std::str::FromStr;
 
 use std::path::Path;
 
-const CONFIG_TAG: &str = "<|CONFIG|>";
-const ENDCONFIG_TAG: &str = "<|ENDCONFIG|>";
-
 const PROMPT_TAG: &str = "<|PROMPT|>";
 
-const CHAR_TAG: &str = "<|CHAR|>";
-const ENDCHAR_TAG: &str = "<|ENDCHAR|>";
-
-const CONTEXT_TAG: &str = "<|CONTEXT";
-const ENDCONTEXT_TAG: &str = "<|ENDCONTEXT|>";
-const END_TAG: &str = "|>";
-
-impl FromStr for Prompt {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        let config: Config = {
-            let Some(i) = s.find(CONFIG_TAG) else {
-                bail!("No config found!")
-            };
-            let s = &s[i + CONFIG_TAG.len()..];
+/// The tags of the prompt file format. `INCLUDE` and `JSON` tags are handled before parsing, by
+/// `preprocess_file`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TagKind {
+    Config,
+    EndConfig,
+    Prompt,
+    Char,
+    EndChar,
+    Context,
+    EndContext,
+}
 
-            let Some(j) = s.find(ENDCONFIG_TAG) else {
-                bail!("No config found!")
-            };
+impl TagKind {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "CONFIG" => Self::Config,
+            "ENDCONFIG" => Self::EndConfig,
+            "PROMPT" => Self::Prompt,
+            "CHAR" => Self::Char,
+            "ENDCHAR" => Self::EndChar,
+            "CONTEXT" => Self::Context,
+            "ENDCONTEXT" => Self::EndContext,
+            _ => return None,
+        })
+    }
 
-            serde_yaml::from_str(&s[..j])?
-        };
+    fn name(self) -> &'static str {
+        match self {
+            Self::Config => "<|CONFIG|>",
+            Self::EndConfig => "<|ENDCONFIG|>",
+            Self::Prompt => "<|PROMPT|>",
+            Self::Char => "<|CHAR|>",
+            Self::EndChar => "<|ENDCHAR|>",
+            Self::Context => "<|CONTEXT name|>",
+            Self::EndContext => "<|ENDCONTEXT|>",
+        }
+    }
+}
 
-        let prompt = s
-            .find(PROMPT_TAG)
-            .map(|i| trim_newline_left_right(&s[i + PROMPT_TAG.len()..]).to_string())
-            .unwrap_or_default();
+/// A tag like `<|CHAR|>` or `<|CONTEXT name|>`, and the bytes of the text it covers.
+#[derive(Clone, Copy, Debug)]
+struct Tag<'a> {
+    kind: TagKind,
+    arg: &'a str,
+    span: Span,
+}
 
-        let mut characters = Vec::new();
-        let mut view = s;
+/// Splits the preprocessed text of a prompt file into tags.
+///
+/// A tag is `<|`, an upper case name, optional arguments and `|>`, all on one line. Other text
+/// between `<|` and `|>`, like the `<|im_start|>` special tokens of some models, is left alone.
+struct Lexer<'a> {
+    text: &'a str,
+    pos: usize,
+    source: &'a SourceMap,
+    warnings: Vec<Diagnostic>,
+}
 
-        while let Some(i) = view.find(CHAR_TAG) {
-            view = &view[i + CHAR_TAG.len()..];
+impl<'a> Lexer<'a> {
+    fn error(&self, offset: usize, message: impl Into<String>) -> anyhow::Error {
+        anyhow!("{}", self.source.diagnostic(offset, message))
+    }
 
-            let Some(i) = view.find(ENDCHAR_TAG) else {
-                bail!("Unclosed char tag!")
+    /// Returns the next tag, or `None` at the end of the text.
+    fn next_tag(&mut self) -> Result<Option<Tag<'a>>> {
+        while let Some(i) = self.text[self.pos..].find("<|") {
+            let start = self.pos + i;
+            let line = self.text[start..].lines().next().unwrap_or_default();
+            let inner = &line[2..];
+            self.pos = start + 2;
+
+            let name_len = inner
+                .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+                .unwrap_or(inner.len());
+            let name = &inner[..name_len];
+            let rest = &inner[name_len..];
+
+            if name.is_empty() || !(rest.is_empty() || rest.starts_with([' ', '\t', '|'])) {
+                continue;
+            }
+
+            let Some(end) = rest.find("|>").map(|end| name_len + end) else {
+                if TagKind::from_name(name).is_some() {
+                    return Err(self.error(start, format!("Missing \"|>\" after \"<|{name}\"")));
+                }
+                continue;
             };
-            let char = serde_yaml::from_str::<Character>(&view[..i])?;
 
-            characters.push(char);
+            let arg = inner[name_len..end].trim();
+            let span = Span::new(start, start + 2 + end + 2);
+            self.pos = span.end;
+
+            let Some(kind) = TagKind::from_name(name) else {
+                self.warnings.push(self.source.diagnostic(
+                    start,
+                    format!(
+                        "Unknown tag \"{}\", treated as text",
+                        &self.text[span.start..span.end]
+                    ),
+                ));
+                continue;
+            };
 
-            view = &view[i + ENDCHAR_TAG.len()..];
+            if kind == TagKind::Context && arg.is_empty() {
+                return Err(self.error(start, "<|CONTEXT|> tag needs a character name"));
+            }
+            if kind != TagKind::Context && !arg.is_empty() {
+                return Err(self.error(
+                    start,
+                    format!("Unexpected argument \"{arg}\" in {} tag", kind.name()),
+                ));
+            }
+
+            return Ok(Some(Tag { kind, arg, span }));
         }
 
-        let mut contexts = HashMap::new();
-        view = s;
+        self.pos = self.text.len();
+        Ok(None)
+    }
 
-        while let Some(i) = view.find(CONTEXT_TAG) {
-            view = &view[i + CONTEXT_TAG.len()..];
+    /// Returns the tag closing `open`, which must be the next tag and of kind `end`.
+    fn closing_tag(&mut self, open: &Tag, end: TagKind) -> Result<Tag<'a>> {
+        match self.next_tag()? {
+            Some(tag) if tag.kind == end => Ok(tag),
+            Some(tag) => Err(self.error(
+                tag.span.start,
+                format!(
+                    "Expected {} to close the tag at {}, found {}",
+                    end.name(),
+                    self.source.locate(open.span.start),
+                    tag.kind.name()
+                ),
+            )),
+            None => Err(self.error(
+                open.span.start,
+                format!("Unclosed {} tag, expected {}", open.kind.name(), end.name()),
+            )),
+        }
+    }
 
-            let Some(i) = view.find(END_TAG) else {
-                break;
+    /// Parses the YAML between `open` and `close`, pointing errors at the text they occur in.
+    fn parse_yaml<T: serde::de::DeserializeOwned>(&self, open: &Tag, close: &Tag) -> Result<T> {
+        let body = &self.text[open.span.end..close.span.start];
+
+        serde_yaml::from_str(body).map_err(|e| {
+            let offset = e
+                .location()
+                .map_or(open.span.end, |loc| open.span.end + loc.index());
+            // The location is reported separately, relative to the whole file.
+            let message = e.to_string();
+            let message = match message.rfind(" at line ") {
+                Some(i) if e.location().is_some() => message[..i].to_string(),
+                _ => message,
             };
 
-            let name = view[..i].trim();
-            view = &view[i + END_TAG.len()..];
+            self.error(offset, message)
+        })
+    }
+}
 
-            let Some(i) = view.find(ENDCONTEXT_TAG) else {
-                bail!("Unclosed context tag!")
-            };
+impl Prompt {
+    /// Parses the preprocessed text of a prompt file. `source` maps the text back to the files
+    /// it was read from, and records where each part of the prompt is defined.
+    pub fn parse(text: &str, mut source: SourceMap) -> Result<Prompt> {
+        let mut lexer = Lexer {
+            text,
+            pos: 0,
+            source: &source,
+            warnings: Vec::new(),
+        };
 
-            let definition = trim_newline_left_right(&view[..i]);
-            contexts.insert(
-                name.to_string(),
-                replace_char_user(definition, name, &config.user_name),
-            );
+        let mut config: Option<(Config, Span)> = None;
+        let mut characters = Vec::new();
+        let mut character_spans = Vec::new();
+        let mut contexts = HashMap::new();
+        let mut context_spans = BTreeMap::new();
+        let mut prompt = String::new();
+        let mut prompt_span = None;
+
+        while let Some(tag) = lexer.next_tag()? {
+            match tag.kind {
+                TagKind::Config => {
+                    let close = lexer.closing_tag(&tag, TagKind::EndConfig)?;
+
+                    if let Some((_, span)) = &config {
+                        return Err(lexer.error(
+                            tag.span.start,
+                            format!(
+                                "Duplicate config, the first is at {}",
+                                lexer.source.locate(span.start)
+                            ),
+                        ));
+                    }
+
+                    let span = Span::new(tag.span.start, close.span.end);
+                    config = Some((lexer.parse_yaml(&tag, &close)?, span));
+                }
+                TagKind::Char => {
+                    let close = lexer.closing_tag(&tag, TagKind::EndChar)?;
+
+                    characters.push(lexer.parse_yaml::<Character>(&tag, &close)?);
+                    character_spans.push(Span::new(tag.span.start, close.span.end));
+                }
+                TagKind::Context => {
+                    let close = lexer.closing_tag(&tag, TagKind::EndContext)?;
+                    let span = Span::new(tag.span.start, close.span.end);
+
+                    if let Some(first) = context_spans.insert(tag.arg.to_string(), span) {
+                        let warning = lexer.source.diagnostic(
+                            tag.span.start,
+                            format!(
+                                "Context \"{}\" was already defined at {}, this definition \
+                                replaces it",
+                                tag.arg,
+                                lexer.source.locate(first.start)
+                            ),
+                        );
+                        lexer.warnings.push(warning);
+                    }
+
+                    let definition = &text[tag.span.end..close.span.start];
+                    contexts.insert(
+                        tag.arg.to_string(),
+                        trim_newline_left_right(definition).to_string(),
+                    );
+                }
+                TagKind::Prompt => {
+                    // Everything after the prompt tag is the prompt, even if it looks like tags.
+                    prompt = trim_newline_left_right(&text[tag.span.end..]).to_string();
+                    prompt_span = Some(Span::new(tag.span.end, text.len()));
+                    break;
+                }
+                TagKind::EndConfig | TagKind::EndChar | TagKind::EndContext => {
+                    return Err(lexer.error(
+                        tag.span.start,
+                        format!("{} tag without an opening tag", tag.kind.name()),
+                    ));
+                }
+            }
+        }
+
+        let warnings = lexer.warnings;
+
+        let Some((config, config_span)) = config else {
+            bail!("No config found!")
+        };
 
-            view = &view[i + ENDCONTEXT_TAG.len()..];
+        for (name, definition) in &mut contexts {
+            *definition = replace_char_user(definition, name, &config.user_name);
         }
 
+        source.config = Some(config_span);
+        source.characters = character_spans;
+        source.contexts = context_spans;
+        source.prompt = prompt_span;
+
         Ok(Prompt {
             config,
             characters,
             contexts,
             prompt,
+            source,
+            warnings,
         })
     }
 }
 
+impl FromStr for Prompt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Prompt::parse(s, SourceMap::for_text(s))
+    }
+}
+
 pub fn parse_from_file<T: FromStr>(path: impl AsRef<Path>) -> Result<T>
 where
     anyhow::Error: From<T::Err>,
@@ -96,6 +289,62 @@ where
     Ok(preprocess_file(path)?.parse()?)
 }
 
+/// Parses the prompt file at `path`, and reads the grammar files of its characters.
+pub fn load_prompt(path: impl AsRef<Path>) -> Result<Prompt> {
+    let (text, source) = preprocess_with_map(&path)?;
+    let mut prompt = Prompt::parse(&text, source)?;
+
+    if let Some((_, e)) = read_grammar_files(&mut prompt).into_iter().next() {
+        return Err(e);
+    }
+    Ok(prompt)
+}
+
+/// Reads the grammar files of the characters of `prompt`, relative to the file which defines
+/// each character. Returns the index of each character whose grammar couldn't be read, along
+/// with why.
+pub fn read_grammar_files(prompt: &mut Prompt) -> Vec<(usize, anyhow::Error)> {
+    let mut failed = Vec::new();
+
+    for (i, char) in prompt.characters.iter_mut().enumerate() {
+        let Some(grammar_file) = &char.grammar_file else {
+            continue;
+        };
+
+        if char.grammar.is_some() {
+            let e = anyhow!(
+                "Character {} has both a grammar and a grammar file!",
+                char.name
+            );
+            failed.push((i, e));
+            continue;
+        }
+
+        let defined_in = prompt
+            .source
+            .characters
+            .get(i)
+            .and_then(|span| prompt.source.locate(span.start).file);
+        let grammar_path = match defined_in {
+            Some(file) => join_filename(file, grammar_file),
+            None => PathBuf::from(grammar_file),
+        };
+
+        match std::fs::read_to_string(&grammar_path) {
+            Ok(grammar) => char.grammar = Some(grammar),
+            Err(e) => failed.push((
+                i,
+                anyhow!(
+                    "Could not read grammar file {grammar_path:?} of character {}: {e}",
+                    char.name
+                ),
+            )),
+        }
+    }
+
+    failed
+}
+
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 
@@ -140,3 +389,106 @@ pub fn overwrite_prompt_in_file(file: impl AsRef<Path>, prompt: &str) -> Result<
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "<|CONFIG|>\nuser_name: User\n<|ENDCONFIG|>\n";
+    const CHAR: &str = "<|CHAR|>\nname: Bot\n<|ENDCHAR|>\n";
+
+    fn parse_error(text: &str) -> String {
+        Prompt::from_str(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn sections_are_parsed_with_spans() {
+        let text = format!("{CONFIG}<|CONTEXT Bot|>\n{{{{char}}}} is here.\n<|ENDCONTEXT|>\n{CHAR}<|PROMPT|>\nUser: Hi");
+        let prompt = Prompt::from_str(&text).unwrap();
+
+        assert_eq!(prompt.config.user_name, "User");
+        assert_eq!(prompt.characters[0].name, "Bot");
+        assert_eq!(prompt.contexts["Bot"], "Bot is here.");
+        assert_eq!(prompt.prompt, "User: Hi");
+        assert_eq!(
+            prompt.source.locate(prompt.source.characters[0].start).line,
+            7
+        );
+        assert_eq!(
+            prompt
+                .source
+                .locate(prompt.source.contexts["Bot"].start)
+                .line,
+            4
+        );
+    }
+
+    #[test]
+    fn unclosed_tag_points_at_both_tags() {
+        let text = format!("{CONFIG}<|CHAR|>\nname: Bot\n<|PROMPT|>\nHi");
+
+        assert_eq!(
+            parse_error(&text),
+            "line 6, column 1: Expected <|ENDCHAR|> to close the tag at line 4, column 1, found \
+            <|PROMPT|>"
+        );
+    }
+
+    #[test]
+    fn unknown_tags_are_warnings() {
+        let text = format!(
+            "{CONFIG}<|CHAR|>\nname: Bot\ntemporary_prefix: \"<|im_start|>\"\n<|ENDCHAR|>\n\
+            <|CHARS|>\n<|PROMPT|>\nHi"
+        );
+        let prompt = Prompt::from_str(&text).unwrap();
+
+        let warnings: Vec<_> = prompt.warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            warnings,
+            ["line 8, column 1: Unknown tag \"<|CHARS|>\", treated as text"]
+        );
+        assert_eq!(prompt.characters[0].temporary_prefix, "<|im_start|>");
+    }
+
+    #[test]
+    fn context_needs_a_name() {
+        let text = format!("{CONFIG}<|CONTEXT|>\nLore\n<|ENDCONTEXT|>\n");
+
+        assert_eq!(
+            parse_error(&text),
+            "line 4, column 1: <|CONTEXT|> tag needs a character name"
+        );
+    }
+
+    #[test]
+    fn stray_end_tag_is_an_error() {
+        let text = format!("{CONFIG}{CHAR}<|ENDCHAR|>\n");
+
+        assert_eq!(
+            parse_error(&text),
+            "line 7, column 1: <|ENDCHAR|> tag without an opening tag"
+        );
+    }
+
+    #[test]
+    fn duplicate_config_is_an_error() {
+        let text = format!("{CONFIG}{CHAR}{CONFIG}");
+
+        assert_eq!(
+            parse_error(&text),
+            "line 7, column 1: Duplicate config, the first is at line 1, column 1"
+        );
+    }
+
+    #[test]
+    fn yaml_errors_point_into_the_section() {
+        let text = format!("{CONFIG}<|CHAR|>\nname: [Bot\n<|ENDCHAR|>\n");
+
+        assert!(parse_error(&text).starts_with("line 5, column "));
+    }
+
+    #[test]
+    fn missing_config_is_an_error() {
+        assert_eq!(parse_error(CHAR), "No config found!");
+    }
+}
@@ -4,8 +4,10 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
 
+use super::{Include, SourceMap, Span};
+
 /// Computes the path of file2 relative to the directory file1 is in.
-fn join_filename(file1: impl AsRef<Path>, file2: impl AsRef<Path>) -> PathBuf {
+pub(super) fn join_filename(file1: impl AsRef<Path>, file2: impl AsRef<Path>) -> PathBuf {
     let mut file1 = file1.as_ref().to_path_buf();
     file1.pop();
     file1.push(file2);
@@ -59,10 +61,49 @@ const END_TAG: &str = "|>";
 
 /// Reads a file and processes 'INCLUDE' and 'JSON' statements.
 pub fn preprocess_file(file: impl AsRef<Path>) -> Result<String> {
-    let mut contents = String::new();
-    File::open(&file)?.read_to_string(&mut contents)?;
+    Ok(preprocess_with_map(file)?.0)
+}
+
+/// Like `preprocess_file`, but also returns a map from the output back to the files it was
+/// read from.
+pub fn preprocess_with_map(file: impl AsRef<Path>) -> Result<(String, SourceMap)> {
+    preprocess(file, false)
+}
 
+/// Like `preprocess_with_map`, but included files which don't exist are left out instead of
+/// failing, and recorded as unresolved in the map's includes.
+pub fn preprocess_leniently(file: impl AsRef<Path>) -> Result<(String, SourceMap)> {
+    preprocess(file, true)
+}
+
+fn preprocess(file: impl AsRef<Path>, lenient: bool) -> Result<(String, SourceMap)> {
     let mut out = String::new();
+    let mut map = SourceMap::default();
+    preprocess_into(file, &mut out, &mut map, &mut Vec::new(), lenient)?;
+    Ok((out, map))
+}
+
+/// Appends the processed contents of `file` to `out`. `including` holds the files which are
+/// being included, to catch files which include themselves.
+fn preprocess_into(
+    file: impl AsRef<Path>,
+    out: &mut String,
+    map: &mut SourceMap,
+    including: &mut Vec<PathBuf>,
+    lenient: bool,
+) -> Result<()> {
+    let mut contents = String::new();
+    File::open(&file)
+        .with_context(|| format!("Could not open {:?}", file.as_ref()))?
+        .read_to_string(&mut contents)?;
+
+    let canonical = std::fs::canonicalize(&file)?;
+    if including.contains(&canonical) {
+        bail!("{:?} includes itself!", file.as_ref());
+    }
+    including.push(canonical);
+
+    let file_index = map.add_file(Some(file.as_ref()), contents.clone());
 
     let mut tags: Vec<(usize, usize, &str)> = contents
         .match_indices(INCLUDE_TAG)
@@ -82,32 +123,60 @@ pub fn preprocess_file(file: impl AsRef<Path>) -> Result<String> {
             .map(|(_, end, _)| *end + END_TAG.len())
             .unwrap_or(0);
         if prev_tag_end > tag_start {
-            bail!("Missing end tag for Include statement!")
+            let (prev_start, _, prev_tag) = tags[i - 1];
+            bail!(
+                "{}: Missing end tag for {prev_tag} statement!",
+                map.locate_in_file(file_index, prev_start)
+            )
         }
+        map.add_segment(out.len(), file_index, prev_tag_end);
         out.push_str(&contents[prev_tag_end..tag_start]);
 
         let tag_args = &contents[tag_start + tag.len()..tag_end];
 
-        let replacement = match tag {
-            INCLUDE_TAG => preprocess_file(join_filename(&file, tag_args.trim()))?,
+        match tag {
+            INCLUDE_TAG => {
+                let path = join_filename(&file, tag_args.trim());
+                let start = out.len();
+                let index = map.includes.len();
+                let resolved = !lenient || path.is_file();
+
+                map.includes.push(Include {
+                    location: map.locate_in_file(file_index, tag_start),
+                    path: path.clone(),
+                    span: Span::new(start, start),
+                    resolved,
+                });
+
+                if resolved {
+                    preprocess_into(path, out, map, including, lenient).with_context(|| {
+                        format!(
+                            "{}: Could not include file",
+                            map.locate_in_file(file_index, tag_start)
+                        )
+                    })?;
+                }
+                map.includes[index].span.end = out.len();
+            }
             INCLUDE_JSON_TAG => {
                 let mut iter = tag_args.split_whitespace();
                 let json_file = iter.next().context("Too few arguments for JSON tag!")?;
                 let json_pointer = iter.next().context("Too few arguments for JSON tag!")?;
-                extract_json_string(json_file, json_pointer)?
+                // Point the inserted string at the tag which inserted it.
+                map.add_segment(out.len(), file_index, tag_start);
+                out.push_str(&extract_json_string(json_file, json_pointer)?);
             }
             _ => unreachable!(),
-        };
-
-        out.push_str(&replacement);
+        }
     }
 
-    out.push_str(
-        &contents[tags
-            .last()
-            .map(|(_, end, _)| *end + END_TAG.len())
-            .unwrap_or(0)..],
-    );
+    let rest = tags
+        .last()
+        .map(|(_, end, _)| *end + END_TAG.len())
+        .unwrap_or(0);
+    map.add_segment(out.len(), file_index, rest);
+    out.push_str(&contents[rest..]);
 
-    Ok(out)
+    including.pop();
+    Ok(())
 }
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A range of bytes in the preprocessed text of a prompt file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+/// A line and column in a source file, both starting at 1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// `None` for text which wasn't read from a file.
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file.display(), self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+/// A problem found in a prompt file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub(crate) location: Location,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SourceFile {
+    path: Option<PathBuf>,
+    contents: String,
+}
+
+/// Part of the preprocessed text, copied from `file` starting at `offset`.
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    start: usize,
+    file: usize,
+    offset: usize,
+}
+
+/// An `<|INCLUDE|>` tag, and where the text it included is in the preprocessed text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Include {
+    /// Where the tag is.
+    pub(crate) location: Location,
+    pub(crate) path: PathBuf,
+    pub(crate) span: Span,
+    /// Whether the file could be read. Only `preprocess_leniently` leaves out such files.
+    pub(crate) resolved: bool,
+}
+
+/// Maps the preprocessed text of a prompt file back to the files it was read from, and records
+/// where each part of the prompt was defined.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    /// Sorted by start.
+    segments: Vec<Segment>,
+    pub(crate) config: Option<Span>,
+    /// The definition of each character, in the same order as `Prompt::characters`.
+    pub(crate) characters: Vec<Span>,
+    pub(crate) contexts: BTreeMap<String, Span>,
+    /// The prompt text, after the `<|PROMPT|>` tag.
+    pub(crate) prompt: Option<Span>,
+    /// Every `<|INCLUDE|>` tag, in the order they were processed.
+    pub(crate) includes: Vec<Include>,
+}
+
+impl SourceMap {
+    /// Returns a map for `text`, which wasn't read from a file.
+    pub fn for_text(text: &str) -> Self {
+        let mut map = Self::default();
+        let file = map.add_file(None, text.to_string());
+        map.add_segment(0, file, 0);
+        map
+    }
+
+    /// Registers a source file, returning its index.
+    pub(super) fn add_file(&mut self, path: Option<&Path>, contents: String) -> usize {
+        self.files.push(SourceFile {
+            path: path.map(Path::to_path_buf),
+            contents,
+        });
+        self.files.len() - 1
+    }
+
+    /// Records that the preprocessed text from `start` on is copied from `file` at `offset`.
+    pub(super) fn add_segment(&mut self, start: usize, file: usize, offset: usize) {
+        self.segments.push(Segment {
+            start,
+            file,
+            offset,
+        });
+    }
+
+    /// Returns the location in its source file of `offset` in the preprocessed text.
+    pub fn locate(&self, offset: usize) -> Location {
+        let Some(segment) = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.start <= offset)
+        else {
+            return Location {
+                file: None,
+                line: 1,
+                column: 1,
+            };
+        };
+
+        self.locate_in_file(segment.file, segment.offset + offset - segment.start)
+    }
+
+    /// Returns the location of `offset` in the source file with index `file`.
+    pub(super) fn locate_in_file(&self, file: usize, offset: usize) -> Location {
+        let file = &self.files[file];
+        let mut offset = offset.min(file.contents.len());
+        while !file.contents.is_char_boundary(offset) {
+            offset -= 1;
+        }
+
+        let before = &file.contents[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+
+        Location {
+            file: file.path.clone(),
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    pub fn diagnostic(&self, offset: usize, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            location: self.locate(offset),
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::preprocess_with_map;
+
+    #[test]
+    fn text_is_located_by_line_and_column() {
+        let map = SourceMap::for_text("one\ntwo\nthree");
+
+        assert_eq!(map.locate(0).to_string(), "line 1, column 1");
+        assert_eq!(map.locate(5).to_string(), "line 2, column 2");
+        assert_eq!(map.locate(100).to_string(), "line 3, column 6");
+    }
+
+    #[test]
+    fn included_text_is_located_in_its_file() {
+        let dir = std::env::temp_dir();
+        let name = |file: &str| format!("kobold_cli_source_{}_{file}.txt", std::process::id());
+        let (main, included) = (dir.join(name("main")), dir.join(name("included")));
+
+        std::fs::write(&included, "alpha\nbeta").unwrap();
+        std::fs::write(
+            &main,
+            format!("start\n<|INCLUDE {}|>\nend", name("included")),
+        )
+        .unwrap();
+
+        let (text, map) = preprocess_with_map(&main).unwrap();
+        let locate = |needle: &str| map.locate(text.find(needle).unwrap()).to_string();
+
+        let _ = std::fs::remove_file(&main);
+        let _ = std::fs::remove_file(&included);
+
+        assert_eq!(text, "start\nalpha\nbeta\nend");
+        assert_eq!(locate("start"), format!("{}:1:1", main.display()));
+        assert_eq!(locate("alpha"), format!("{}:1:1", included.display()));
+        assert_eq!(locate("beta"), format!("{}:2:1", included.display()));
+        assert_eq!(locate("end"), format!("{}:3:1", main.display()));
+        assert_eq!(map.includes[0].location.line, 2);
+    }
+}
@@ -6,5 +6,30 @@ mod server;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    cli::Cli::new().run().await
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let args = match cli::Args::parse(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match args {
+        cli::Args::Repl => cli::Cli::new().run().await,
+        cli::Args::Help => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+        cli::Args::Gen(args) => cli::Cli::new().run_gen(&args).await,
+        cli::Args::Serve => cli::Cli::new().serve_stdio().await,
+        cli::Args::Check(files) => {
+            if !cli::check_files(&files)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        cli::Args::MockServer(args) => server::mock::run_mock_server(&args).await,
+    }
 }
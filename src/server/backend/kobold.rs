@@ -0,0 +1,177 @@
+use super::*;
+use serde_json::json;
+
+/// The samplers KoboldCpp can order, by id. Samplers which aren't listed, like min_p and DRY,
+/// are always applied in a fixed position.
+const SAMPLERS: [&str; 7] = [
+    "top_k",
+    "top_a",
+    "top_p",
+    "tfs",
+    "typical",
+    "temperature",
+    "rep_pen",
+];
+
+/// KoboldCpp's `/api/v1` and `/api/extra` routes.
+pub(super) struct Kobold;
+
+impl Backend for Kobold {
+    fn health(&self, client: Client, url: String) -> BoxFuture<bool> {
+        Box::pin(async move {
+            let res = client
+                .post(format!("{url}/api/v1/info/version"))
+                .header("Content-Length", "0")
+                .send()
+                .await;
+
+            Ok(!is_offline(&res)?)
+        })
+    }
+
+    fn generate(&self, client: Client, url: String, prompt: ServerPrompt) -> BoxFuture<String> {
+        Box::pin(async move {
+            let json: Value = client
+                .post(format!("{url}/api/v1/generate"))
+                .json(&prompt)
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            get_str(&json, "/results/0/text")
+        })
+    }
+
+    fn stream(
+        &self,
+        client: Client,
+        url: String,
+        prompt: ServerPrompt,
+    ) -> BoxFuture<Option<Response>> {
+        Box::pin(async move {
+            let res = client
+                .post(format!("{url}/api/extra/generate/stream"))
+                .json(&prompt)
+                .send()
+                .await?;
+
+            streaming_response(res)
+        })
+    }
+
+    fn parse_stream_data(&self, data: &str) -> Result<Option<String>> {
+        let json: Value = serde_json::from_str(data)?;
+        get_str(&json, "/token").map(Some)
+    }
+
+    fn check(&self, client: Client, url: String) -> BoxFuture<Option<String>> {
+        Box::pin(async move {
+            let json: Value = client
+                .post(format!("{url}/api/extra/generate/check"))
+                .header("Content-Length", "0")
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            get_str(&json, "/results/0/text").map(Some)
+        })
+    }
+
+    fn abort(&self, client: Client, url: String) -> BoxFuture<()> {
+        Box::pin(async move {
+            client
+                .post(format!("{url}/api/extra/abort"))
+                .header("Content-Length", "0")
+                .send()
+                .await?;
+
+            Ok(())
+        })
+    }
+
+    fn tokenize(&self, client: Client, url: String, text: String) -> BoxFuture<Option<Vec<u32>>> {
+        Box::pin(async move {
+            let res = client
+                .post(format!("{url}/api/extra/tokencount"))
+                .json(&json!({ "prompt": text }))
+                .send()
+                .await?;
+
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+
+            let json: Value = res.json().await?;
+
+            // Older versions of KoboldCpp only return the count.
+            Ok(json.pointer("/ids").and_then(Value::as_array).map(|ids| {
+                ids.iter()
+                    .filter_map(Value::as_u64)
+                    .map(|id| id as u32)
+                    .collect()
+            }))
+        })
+    }
+
+    fn info(&self, client: Client, url: String) -> BoxFuture<ServerInfo> {
+        Box::pin(async move {
+            let model = get_json(&client, &format!("{url}/api/v1/model")).await?;
+            let version = get_json(&client, &format!("{url}/api/extra/version")).await?;
+            let perf = get_json(&client, &format!("{url}/api/extra/perf")).await?;
+
+            // Older versions of KoboldCpp only report the context size of the current request.
+            let mut context =
+                get_json(&client, &format!("{url}/api/extra/true_max_context_length")).await?;
+            if context.is_none() {
+                context =
+                    get_json(&client, &format!("{url}/api/v1/config/max_context_length")).await?;
+            }
+
+            Ok(ServerInfo {
+                model: lookup(&model, "/result")
+                    .and_then(Value::as_str)
+                    .map(Into::into),
+                version: lookup(&version, "/version")
+                    .and_then(Value::as_str)
+                    .map(Into::into),
+                context_length: lookup(&context, "/value")
+                    .and_then(Value::as_u64)
+                    .map(|n| n as usize),
+                last_tokens: lookup(&perf, "/last_token_count").and_then(Value::as_u64),
+                last_eval_time: lookup(&perf, "/last_eval").and_then(Value::as_f64),
+                total_gens: lookup(&perf, "/total_gens").and_then(Value::as_u64),
+                queue: lookup(&perf, "/queue").and_then(Value::as_u64),
+            })
+        })
+    }
+
+    fn check_prompt(&self, prompt: &ServerPrompt) -> Result<()> {
+        let mut seen = [false; SAMPLERS.len()];
+
+        for &id in &prompt.sampler_order {
+            let Some(seen) = seen.get_mut(id) else {
+                bail!(
+                    "Unknown sampler {id} in sampler_order! KoboldCpp supports {}.",
+                    SAMPLERS
+                        .iter()
+                        .enumerate()
+                        .map(|(i, name)| format!("{i} ({name})"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            };
+
+            if *seen {
+                bail!(
+                    "Sampler {id} ({}) appears twice in sampler_order!",
+                    SAMPLERS[id]
+                );
+            }
+            *seen = true;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use super::*;
+use serde_json::json;
+
+/// The `/completion` route of llama.cpp's server.
+pub(super) struct LlamaCpp;
+
+fn request_body(prompt: &ServerPrompt, stream: bool) -> Value {
+    let logit_bias: Vec<_> = prompt
+        .logit_bias
+        .iter()
+        .map(|(token, bias)| json!([token, bias]))
+        .collect();
+
+    json!({
+        "prompt": prompt.prompt,
+        "n_predict": prompt.max_length,
+        "temperature": prompt.temperature,
+        "top_k": prompt.top_k,
+        "top_p": prompt.top_p,
+        "typical_p": prompt.typical,
+        "tfs_z": prompt.tfs,
+        "repeat_penalty": prompt.rep_pen,
+        "repeat_last_n": prompt.rep_pen_range,
+        "stop": prompt.stop_sequence,
+        "seed": prompt.sampler_seed,
+        "cache_prompt": true,
+        "stream": stream,
+        "logit_bias": logit_bias,
+        "grammar": prompt.grammar,
+        "min_p": prompt.min_p,
+        "mirostat": prompt.mirostat,
+        "mirostat_tau": prompt.mirostat_tau,
+        "mirostat_eta": prompt.mirostat_eta,
+        "dynatemp_range": prompt.dynatemp_range,
+        "dynatemp_exponent": prompt.dynatemp_exponent,
+        "dry_multiplier": prompt.dry_multiplier,
+        "dry_base": prompt.dry_base,
+        "dry_allowed_length": prompt.dry_allowed_length,
+        "dry_penalty_last_n": prompt.dry_penalty_last_n,
+        "dry_sequence_breakers": prompt.dry_sequence_breakers,
+        "xtc_threshold": prompt.xtc_threshold,
+        "xtc_probability": prompt.xtc_probability,
+    })
+}
+
+impl Backend for LlamaCpp {
+    fn health(&self, client: Client, url: String) -> BoxFuture<bool> {
+        Box::pin(async move {
+            let res = client.get(format!("{url}/health")).send().await;
+
+            if is_offline(&res)? {
+                return Ok(false);
+            }
+
+            // The server responds with 503 while the model is loading.
+            Ok(res?.status().is_success())
+        })
+    }
+
+    fn generate(&self, client: Client, url: String, prompt: ServerPrompt) -> BoxFuture<String> {
+        Box::pin(async move {
+            let json: Value = client
+                .post(format!("{url}/completion"))
+                .json(&request_body(&prompt, false))
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            get_str(&json, "/content")
+        })
+    }
+
+    fn stream(
+        &self,
+        client: Client,
+        url: String,
+        prompt: ServerPrompt,
+    ) -> BoxFuture<Option<Response>> {
+        Box::pin(async move {
+            let res = client
+                .post(format!("{url}/completion"))
+                .json(&request_body(&prompt, true))
+                .send()
+                .await?;
+
+            streaming_response(res)
+        })
+    }
+
+    fn parse_stream_data(&self, data: &str) -> Result<Option<String>> {
+        let json: Value = serde_json::from_str(data)?;
+        get_str(&json, "/content").map(Some)
+    }
+
+    fn check(&self, _client: Client, _url: String) -> BoxFuture<Option<String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn abort(&self, _client: Client, _url: String) -> BoxFuture<()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn can_abort(&self) -> bool {
+        false
+    }
+
+    fn tokenize(&self, client: Client, url: String, text: String) -> BoxFuture<Option<Vec<u32>>> {
+        Box::pin(async move {
+            let json: Value = client
+                .post(format!("{url}/tokenize"))
+                .json(&json!({ "content": text }))
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            Ok(json
+                .pointer("/tokens")
+                .and_then(Value::as_array)
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(Value::as_u64)
+                        .map(|id| id as u32)
+                        .collect()
+                }))
+        })
+    }
+
+    fn info(&self, client: Client, url: String) -> BoxFuture<ServerInfo> {
+        Box::pin(async move {
+            let props = get_json(&client, &format!("{url}/props")).await?;
+
+            Ok(ServerInfo {
+                model: lookup(&props, "/model_path")
+                    .and_then(Value::as_str)
+                    .map(Into::into),
+                version: lookup(&props, "/build_info")
+                    .and_then(Value::as_str)
+                    .map(Into::into),
+                context_length: lookup(&props, "/default_generation_settings/n_ctx")
+                    .and_then(Value::as_u64)
+                    .map(|n| n as usize),
+                ..ServerInfo::default()
+            })
+        })
+    }
+
+    fn check_prompt(&self, prompt: &ServerPrompt) -> Result<()> {
+        if !prompt.banned_tokens.is_empty() {
+            bail!("llama.cpp servers don't support banned_tokens! Use logit_bias instead.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_body_maps_settings() {
+        let prompt = ServerPrompt {
+            max_length: 20,
+            typical: 0.8,
+            tfs: 0.7,
+            rep_pen: 1.1,
+            rep_pen_range: 64,
+            logit_bias: [(5, -100.0), (9, 2.5)].into(),
+            ..ServerPrompt::default()
+        };
+        let body = request_body(&prompt, true);
+
+        assert_eq!(body["n_predict"], 20);
+        assert_eq!(body["typical_p"], 0.8);
+        assert_eq!(body["tfs_z"], 0.7);
+        assert_eq!(body["repeat_penalty"], 1.1);
+        assert_eq!(body["repeat_last_n"], 64);
+        assert_eq!(body["logit_bias"], json!([[5, -100.0], [9, 2.5]]));
+        assert_eq!(body["cache_prompt"], true);
+        assert_eq!(body["stream"], true);
+    }
+
+    #[test]
+    fn unsupported_settings_are_rejected() {
+        let prompt = ServerPrompt {
+            banned_tokens: vec!["User".to_string()],
+            ..ServerPrompt::default()
+        };
+
+        assert!(LlamaCpp.check_prompt(&ServerPrompt::default()).is_ok());
+        assert!(LlamaCpp.check_prompt(&prompt).is_err());
+    }
+
+    #[test]
+    fn stream_data_is_parsed() {
+        assert_eq!(
+            LlamaCpp
+                .parse_stream_data(r#"{"content": " Hi", "stop": false}"#)
+                .unwrap(),
+            Some(" Hi".to_string())
+        );
+        assert!(LlamaCpp.parse_stream_data("not json").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+mod kobold;
+mod llamacpp;
+mod openai;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::files::{BackendKind, ServerConfig, ServerPrompt};
+use anyhow::{bail, Result};
+use reqwest::{Client, Response, StatusCode};
+use serde_json::Value;
+
+use kobold::Kobold;
+use llamacpp::LlamaCpp;
+use openai::OpenAi;
+
+/// What a server reports about itself. Anything the server can't report is `None`.
+#[derive(Clone, Debug, Default)]
+pub struct ServerInfo {
+    pub(crate) model: Option<String>,
+    pub(crate) version: Option<String>,
+    /// The context size the server was started with, in tokens.
+    pub(crate) context_length: Option<usize>,
+    /// The number of tokens generated by the last request.
+    pub(crate) last_tokens: Option<u64>,
+    /// Seconds spent generating the tokens of the last request.
+    pub(crate) last_eval_time: Option<f64>,
+    pub(crate) total_gens: Option<u64>,
+    /// The number of requests waiting to be processed.
+    pub(crate) queue: Option<u64>,
+}
+
+pub(super) type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// The HTTP api of an inference server. Every request takes an owned client and url so that the
+/// returned futures can be spawned.
+pub(super) trait Backend: Send + Sync {
+    /// Returns whether the server at `url` is accepting requests.
+    fn health(&self, client: Client, url: String) -> BoxFuture<bool>;
+
+    /// Generates a response to `prompt`, returning once generation is done.
+    fn generate(&self, client: Client, url: String, prompt: ServerPrompt) -> BoxFuture<String>;
+
+    /// Starts generating a response to `prompt` as server-sent events. Returns `None` if the
+    /// server doesn't support streaming.
+    fn stream(
+        &self,
+        client: Client,
+        url: String,
+        prompt: ServerPrompt,
+    ) -> BoxFuture<Option<Response>>;
+
+    /// Extracts the generated text from the data of one server-sent event, or `None` if the event
+    /// carries no text.
+    fn parse_stream_data(&self, data: &str) -> Result<Option<String>>;
+
+    /// Returns the text generated so far by the running request, or `None` if the server can't
+    /// report it.
+    fn check(&self, client: Client, url: String) -> BoxFuture<Option<String>>;
+
+    /// Stops the running request early, which then returns the text generated so far. Does
+    /// nothing for servers without an abort endpoint, see `can_abort`.
+    fn abort(&self, client: Client, url: String) -> BoxFuture<()>;
+
+    /// Whether `abort` stops the running request. Requests to servers which can't be aborted are
+    /// stopped by dropping the connection instead, losing what was generated.
+    fn can_abort(&self) -> bool {
+        true
+    }
+
+    /// Returns the token ids of `text`, or `None` if the server can't tokenize text.
+    fn tokenize(&self, client: Client, url: String, text: String) -> BoxFuture<Option<Vec<u32>>>;
+
+    /// Returns what the server reports about its model and performance.
+    fn info(&self, client: Client, url: String) -> BoxFuture<ServerInfo>;
+
+    /// Checks settings of `prompt` which depend on the server, like the sampler order.
+    fn check_prompt(&self, _prompt: &ServerPrompt) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub(super) fn new_backend(config: &ServerConfig) -> Arc<dyn Backend> {
+    match config.backend {
+        BackendKind::Kobold => Arc::new(Kobold),
+        BackendKind::OpenAi => Arc::new(OpenAi {
+            model: config.api_model.clone(),
+        }),
+        BackendKind::LlamaCpp => Arc::new(LlamaCpp),
+    }
+}
+
+/// Returns whether a request failed because nothing is listening at the url.
+fn is_offline(res: &reqwest::Result<Response>) -> Result<bool> {
+    match res {
+        Ok(_) => Ok(false),
+        Err(e) if e.is_connect() || e.is_request() => Ok(true),
+        Err(e) => bail!("{e}"),
+    }
+}
+
+/// Returns `None` for streaming endpoints the server doesn't have, and fails on other errors.
+fn streaming_response(res: Response) -> Result<Option<Response>> {
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if !res.status().is_success() {
+        bail!("Server responded with {}", res.status());
+    }
+
+    Ok(Some(res))
+}
+
+/// Fetches json from `url`, or `None` if the server doesn't have the endpoint.
+async fn get_json(client: &Client, url: &str) -> Result<Option<Value>> {
+    let res = client.get(url).send().await?;
+
+    if !res.status().is_success() {
+        return Ok(None);
+    }
+
+    Ok(res.json().await.ok())
+}
+
+/// Returns the value at `pointer` of json which may be missing.
+fn lookup<'a>(json: &'a Option<Value>, pointer: &str) -> Option<&'a Value> {
+    json.as_ref()?.pointer(pointer)
+}
+
+fn get_str(json: &Value, pointer: &str) -> Result<String> {
+    let Some(s) = json.pointer(pointer).and_then(Value::as_str) else {
+        bail!("Received invalid json: {json:?}");
+    };
+
+    Ok(s.to_string())
+}
@@ -0,0 +1,212 @@
+use super::*;
+use serde_json::json;
+
+/// The `/v1/completions` route of OpenAI-compatible servers.
+pub(super) struct OpenAi {
+    pub(super) model: Option<String>,
+}
+
+impl OpenAi {
+    fn request_body(&self, prompt: &ServerPrompt, stream: bool) -> Value {
+        let mut body = json!({
+            "prompt": prompt.prompt,
+            "max_tokens": prompt.max_length,
+            "temperature": prompt.temperature,
+            "top_p": prompt.top_p,
+            "seed": prompt.sampler_seed,
+            "stream": stream,
+        });
+
+        if !prompt.stop_sequence.is_empty() {
+            body["stop"] = json!(prompt.stop_sequence);
+        }
+
+        if !prompt.logit_bias.is_empty() {
+            body["logit_bias"] = json!(prompt.logit_bias);
+        }
+
+        if let Some(model) = &self.model {
+            body["model"] = json!(model);
+        }
+
+        body
+    }
+}
+
+impl Backend for OpenAi {
+    fn health(&self, client: Client, url: String) -> BoxFuture<bool> {
+        Box::pin(async move {
+            let res = client.get(format!("{url}/v1/models")).send().await;
+            Ok(!is_offline(&res)?)
+        })
+    }
+
+    fn generate(&self, client: Client, url: String, prompt: ServerPrompt) -> BoxFuture<String> {
+        let body = self.request_body(&prompt, false);
+
+        Box::pin(async move {
+            let json: Value = client
+                .post(format!("{url}/v1/completions"))
+                .json(&body)
+                .send()
+                .await?
+                .json()
+                .await?;
+
+            get_str(&json, "/choices/0/text")
+        })
+    }
+
+    fn stream(
+        &self,
+        client: Client,
+        url: String,
+        prompt: ServerPrompt,
+    ) -> BoxFuture<Option<Response>> {
+        let body = self.request_body(&prompt, true);
+
+        Box::pin(async move {
+            let res = client
+                .post(format!("{url}/v1/completions"))
+                .json(&body)
+                .send()
+                .await?;
+
+            streaming_response(res)
+        })
+    }
+
+    fn parse_stream_data(&self, data: &str) -> Result<Option<String>> {
+        if data == "[DONE]" {
+            return Ok(None);
+        }
+
+        let json: Value = serde_json::from_str(data)?;
+        get_str(&json, "/choices/0/text").map(Some)
+    }
+
+    fn check(&self, _client: Client, _url: String) -> BoxFuture<Option<String>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn abort(&self, _client: Client, _url: String) -> BoxFuture<()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn can_abort(&self) -> bool {
+        false
+    }
+
+    fn tokenize(
+        &self,
+        _client: Client,
+        _url: String,
+        _text: String,
+    ) -> BoxFuture<Option<Vec<u32>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    fn info(&self, client: Client, url: String) -> BoxFuture<ServerInfo> {
+        let model = self.model.clone();
+
+        Box::pin(async move {
+            let models = get_json(&client, &format!("{url}/v1/models")).await?;
+
+            Ok(ServerInfo {
+                model: model.or_else(|| {
+                    lookup(&models, "/data/0/id")
+                        .and_then(Value::as_str)
+                        .map(Into::into)
+                }),
+                ..ServerInfo::default()
+            })
+        })
+    }
+
+    fn check_prompt(&self, prompt: &ServerPrompt) -> Result<()> {
+        if !prompt.banned_tokens.is_empty() {
+            bail!("OpenAI-compatible servers don't support banned_tokens! Use logit_bias instead.");
+        }
+        if !prompt.grammar.is_empty() {
+            bail!("OpenAI-compatible servers don't support grammars!");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_body_maps_settings() {
+        let backend = OpenAi {
+            model: Some("model".to_string()),
+        };
+        let prompt = ServerPrompt {
+            prompt: "Hi".to_string(),
+            max_length: 20,
+            temperature: 0.5,
+            top_p: 0.9,
+            sampler_seed: 7,
+            stop_sequence: vec!["\nUser:".to_string()],
+            logit_bias: [(5, -100.0)].into(),
+            ..ServerPrompt::default()
+        };
+
+        assert_eq!(
+            backend.request_body(&prompt, true),
+            json!({
+                "prompt": "Hi",
+                "max_tokens": 20,
+                "temperature": 0.5,
+                "top_p": 0.9,
+                "seed": 7,
+                "stream": true,
+                "stop": ["\nUser:"],
+                "logit_bias": { "5": -100.0 },
+                "model": "model",
+            })
+        );
+    }
+
+    #[test]
+    fn empty_settings_are_left_out() {
+        let body = OpenAi { model: None }.request_body(&ServerPrompt::default(), false);
+
+        for key in ["stop", "logit_bias", "model"] {
+            assert!(body.get(key).is_none(), "{key} in {body}");
+        }
+    }
+
+    #[test]
+    fn unsupported_settings_are_rejected() {
+        let backend = OpenAi { model: None };
+        let banned = ServerPrompt {
+            banned_tokens: vec!["User".to_string()],
+            ..ServerPrompt::default()
+        };
+        let grammar = ServerPrompt {
+            grammar: "root ::= [a-z]+".to_string(),
+            ..ServerPrompt::default()
+        };
+
+        assert!(backend.check_prompt(&ServerPrompt::default()).is_ok());
+        assert!(backend.check_prompt(&banned).is_err());
+        assert!(backend.check_prompt(&grammar).is_err());
+    }
+
+    #[test]
+    fn stream_data_is_parsed() {
+        let backend = OpenAi { model: None };
+
+        assert_eq!(
+            backend
+                .parse_stream_data(r#"{"choices": [{"text": " Hi"}]}"#)
+                .unwrap(),
+            Some(" Hi".to_string())
+        );
+        assert_eq!(backend.parse_stream_data("[DONE]").unwrap(), None);
+        assert!(backend.parse_stream_data(r#"{"choices": []}"#).is_err());
+    }
+}
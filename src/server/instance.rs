@@ -0,0 +1,515 @@
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use super::backend::Backend;
+use super::logs::{self, TAIL_LINES};
+use super::routing::{BusyCounter, BusyGuard, LastPrompt};
+use crate::files::ServerConfig;
+
+/// How often to check whether an instance that went down is responding again.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before restarting an instance which exited.
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Status {
+    /// The instance was spawned and hasn't come online yet.
+    Starting,
+    /// The instance is accepting requests.
+    Ready,
+    /// The instance exited or stopped responding, and is being restarted or waited on.
+    Down,
+    /// The instance exited before coming online, and won't be restarted.
+    Failed,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Status::Starting => "starting",
+            Status::Ready => "ready",
+            Status::Down => "down",
+            Status::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An instance's status, shared with its supervisor and any requests running on it.
+#[derive(Clone, Debug)]
+pub(super) struct SharedStatus(Arc<AtomicU8>);
+
+impl SharedStatus {
+    fn new(status: Status) -> Self {
+        Self(Arc::new(AtomicU8::new(status as u8)))
+    }
+
+    pub(super) fn get(&self) -> Status {
+        match self.0.load(Ordering::SeqCst) {
+            0 => Status::Starting,
+            1 => Status::Ready,
+            2 => Status::Down,
+            _ => Status::Failed,
+        }
+    }
+
+    fn set(&self, status: Status) {
+        self.0.store(status as u8, Ordering::SeqCst);
+    }
+}
+
+pub(super) fn local_url(port: u16) -> String {
+    format!("http://127.0.0.1:{port}")
+}
+
+/// Asks the OS for `count` distinct ports which are currently free.
+pub(super) fn free_ports(count: usize) -> Result<Vec<u16>> {
+    // Keep every listener open until all ports are picked, so that none are picked twice.
+    let listeners = (0..count)
+        .map(|_| std::net::TcpListener::bind("127.0.0.1:0"))
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("Could not find a free port")?;
+
+    listeners
+        .iter()
+        .map(|listener| Ok(listener.local_addr()?.port()))
+        .collect()
+}
+
+/// Returns whether `err` was caused by failing to reach the server at all.
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// Held by a running request. Marks its instance as busy, and takes the instance out of routing
+/// if the request couldn't reach it.
+#[derive(Debug)]
+pub(super) struct Lease {
+    _busy: BusyGuard,
+    status: SharedStatus,
+    last_failed: Arc<AtomicBool>,
+    cache_lost: Arc<AtomicBool>,
+    succeeded: AtomicBool,
+}
+
+impl Lease {
+    pub(super) fn report<T>(&self, res: &Result<T>) {
+        self.last_failed.store(res.is_err(), Ordering::SeqCst);
+        self.succeeded.store(res.is_ok(), Ordering::SeqCst);
+
+        if let Err(e) = res {
+            if is_connection_error(e) && self.status.get() == Status::Ready {
+                self.status.set(Status::Down);
+            }
+        }
+    }
+}
+
+impl Drop for Lease {
+    /// A request which failed or was dropped may have left anything in the server's cache.
+    fn drop(&mut self) {
+        if !self.succeeded.load(Ordering::SeqCst) {
+            self.cache_lost.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Aborts a background task when dropped.
+#[derive(Debug)]
+pub(super) struct AbortOnDrop(pub(super) JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct Instance {
+    pub(super) url: String,
+    /// The port of a spawned instance.
+    pub(super) port: Option<u16>,
+    /// The name of a spawned instance's log.
+    pub(super) log_name: Option<String>,
+    pub(super) last_prompt: LastPrompt,
+    pub(super) busy: BusyCounter,
+    pub(super) status: SharedStatus,
+    /// Whether the last request on this instance failed. Such instances are only routed to when
+    /// no others are ready.
+    last_failed: Arc<AtomicBool>,
+    /// Set when a request didn't finish, so that the contents of the cache are unknown.
+    cache_lost: Arc<AtomicBool>,
+    /// The settings a spawned instance runs with. Sending new settings restarts it.
+    config: Option<Arc<watch::Sender<ServerConfig>>>,
+    /// Dropping the supervisor kills the instance's process.
+    _supervisor: AbortOnDrop,
+}
+
+impl Instance {
+    /// Spawns a KoboldCpp process on `port`, which is restarted whenever it exits. Its output
+    /// goes to the log named `log_name`.
+    pub(super) fn spawn(
+        backend: Arc<dyn Backend>,
+        client: Client,
+        config: &ServerConfig,
+        log_name: String,
+        port: u16,
+    ) -> Result<Instance> {
+        let config = launch_config(config);
+        let child = spawn_server(config.clone(), port, &log_name)?;
+        let url = local_url(port);
+        let status = SharedStatus::new(Status::Starting);
+        let (config, config_recv) = watch::channel(config);
+        let cache_lost = Arc::<AtomicBool>::default();
+
+        let supervisor = tokio::spawn(supervise(
+            backend,
+            client,
+            config_recv,
+            Slot {
+                port,
+                log_name: log_name.clone(),
+            },
+            child,
+            status.clone(),
+            cache_lost.clone(),
+        ));
+
+        Ok(Instance {
+            url,
+            port: Some(port),
+            log_name: Some(log_name),
+            last_prompt: LastPrompt::default(),
+            busy: BusyCounter::default(),
+            status,
+            last_failed: Arc::default(),
+            cache_lost,
+            config: Some(Arc::new(config)),
+            _supervisor: AbortOnDrop(supervisor),
+        })
+    }
+
+    /// Uses an already running server, which is taken out of routing while it doesn't respond.
+    pub(super) fn attach(
+        backend: Arc<dyn Backend>,
+        client: Client,
+        url: String,
+        online: bool,
+    ) -> Instance {
+        let status = SharedStatus::new(if online { Status::Ready } else { Status::Down });
+
+        let monitor = tokio::spawn({
+            let (url, status) = (url.clone(), status.clone());
+            async move {
+                loop {
+                    tokio::time::sleep(HEALTH_INTERVAL).await;
+                    recover(&*backend, &client, &url, &status).await;
+                }
+            }
+        });
+
+        Instance {
+            url,
+            port: None,
+            log_name: None,
+            last_prompt: LastPrompt::default(),
+            busy: BusyCounter::default(),
+            status,
+            last_failed: Arc::default(),
+            cache_lost: Arc::default(),
+            config: None,
+            _supervisor: AbortOnDrop(monitor),
+        }
+    }
+
+    pub(super) fn is_ready(&self) -> bool {
+        self.status.get() == Status::Ready
+    }
+
+    pub(super) fn last_failed(&self) -> bool {
+        self.last_failed.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn lease(&self) -> Lease {
+        Lease {
+            _busy: self.busy.acquire(),
+            status: self.status.clone(),
+            last_failed: self.last_failed.clone(),
+            cache_lost: self.cache_lost.clone(),
+            succeeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Forgets the last prompt if a request didn't finish since it was sent.
+    pub(super) fn forget_lost_cache(&mut self) {
+        if self.cache_lost.swap(false, Ordering::SeqCst) {
+            self.last_prompt = LastPrompt::default();
+        }
+    }
+
+    /// Describes the instance in messages to the user.
+    pub(super) fn name(&self) -> String {
+        match self.port {
+            Some(port) => format!("Instance on port {port}"),
+            None => format!("Server at {}", self.url),
+        }
+    }
+
+    /// Returns the last lines of a spawned instance's output.
+    pub(super) fn log_tail(&self) -> Option<String> {
+        let config = self.config.as_ref()?.borrow();
+        let log = logs::log_path(&config, self.log_name.as_ref()?);
+        Some(logs::tail(&log, TAIL_LINES))
+    }
+
+    /// Returns whether this instance was spawned with the settings in `config`.
+    pub(super) fn runs_with(&self, config: &ServerConfig) -> bool {
+        self.config
+            .as_ref()
+            .is_some_and(|current| *current.borrow() == launch_config(config))
+    }
+
+    /// Returns a handle which can restart a spawned instance.
+    pub(super) fn handle(&self) -> Option<InstanceHandle> {
+        Some(InstanceHandle {
+            name: self.name(),
+            port: self.port?,
+            status: self.status.clone(),
+            config: self.config.clone()?,
+        })
+    }
+}
+
+/// Restarts a spawned instance, independently of the `Pool` which owns it.
+#[derive(Clone, Debug)]
+pub(super) struct InstanceHandle {
+    pub(super) name: String,
+    pub(super) port: u16,
+    status: SharedStatus,
+    config: Arc<watch::Sender<ServerConfig>>,
+}
+
+impl InstanceHandle {
+    /// Takes the instance out of routing and restarts it with `config`.
+    pub(super) fn restart(&self, config: &ServerConfig) {
+        self.status.set(Status::Starting);
+        self.config.send_replace(launch_config(config));
+    }
+
+    pub(super) fn status(&self) -> Status {
+        self.status.get()
+    }
+}
+
+/// Returns the settings of `config` which affect each spawned instance's process.
+fn launch_config(config: &ServerConfig) -> ServerConfig {
+    ServerConfig {
+        instances: 1,
+        port: None,
+        startup_timeout: 0,
+        rolling_restart: false,
+        routing: Default::default(),
+        timeouts: Default::default(),
+        ..config.clone()
+    }
+}
+
+/// Puts an instance which was marked down back into routing once it responds.
+async fn recover(backend: &dyn Backend, client: &Client, url: &str, status: &SharedStatus) {
+    if status.get() == Status::Down
+        && matches!(
+            backend.health(client.clone(), url.to_string()).await,
+            Ok(true)
+        )
+    {
+        status.set(Status::Ready);
+        eprintln!("Server at {url} is back online.");
+    }
+}
+
+fn describe_exit(exit: std::io::Result<ExitStatus>) -> String {
+    match exit {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// The port and log of a spawned instance, which stay the same when it restarts.
+struct Slot {
+    port: u16,
+    log_name: String,
+}
+
+/// Waits for a spawned instance to come online, then restarts it with the same port whenever it
+/// exits or its settings change. Instances which exit before ever coming online are given up on.
+/// A restarted instance starts with an empty cache, so `cache_lost` is set.
+async fn supervise(
+    backend: Arc<dyn Backend>,
+    client: Client,
+    mut config: watch::Receiver<ServerConfig>,
+    slot: Slot,
+    mut child: Child,
+    status: SharedStatus,
+    cache_lost: Arc<AtomicBool>,
+) {
+    let Slot { port, log_name } = slot;
+    let url = local_url(port);
+    let mut restarted = false;
+
+    loop {
+        let log = logs::log_path(&config.borrow(), &log_name);
+
+        let online = loop {
+            tokio::select! {
+                exit = child.wait() => {
+                    // Failures during the first startup are reported by `Pool::from_config`.
+                    if restarted {
+                        eprintln!(
+                            "Instance on port {port} exited before coming online: {}\n{}",
+                            describe_exit(exit),
+                            logs::tail(&log, TAIL_LINES),
+                        );
+                    }
+                    status.set(Status::Failed);
+                    return;
+                }
+                Ok(()) = config.changed() => break false,
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {}
+            }
+
+            if let Ok(true) = backend.health(client.clone(), url.clone()).await {
+                break true;
+            }
+        };
+
+        if online {
+            status.set(Status::Ready);
+
+            if restarted {
+                eprintln!("Instance on port {port} is back online.");
+            }
+
+            let exit = loop {
+                tokio::select! {
+                    exit = child.wait() => break Some(exit),
+                    Ok(()) = config.changed() => break None,
+                    _ = tokio::time::sleep(HEALTH_INTERVAL) => {
+                        recover(&*backend, &client, &url, &status).await;
+                    }
+                }
+            };
+
+            if let Some(exit) = exit {
+                status.set(Status::Down);
+                eprintln!(
+                    "Instance on port {port} exited ({}), restarting...\n{}",
+                    describe_exit(exit),
+                    logs::tail(&log, TAIL_LINES),
+                );
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
+        }
+
+        let _ = child.kill().await;
+        let config = config.borrow_and_update().clone();
+
+        child = match spawn_server(config, port, &log_name) {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Failed to restart instance on port {port}: {e}");
+                status.set(Status::Failed);
+                return;
+            }
+        };
+        cache_lost.store(true, Ordering::SeqCst);
+        restarted = true;
+    }
+}
+
+/// Spawns KoboldCpp on `port`, writing its output to a freshly rotated log named `log_name`.
+fn spawn_server(config: ServerConfig, port: u16, log_name: &str) -> Result<Child> {
+    let log = logs::rotate(&logs::log_path(&config, log_name))?;
+    let mut cmd = std::process::Command::new(&config.executable_file);
+
+    cmd.args(["--skiplauncher"])
+        .args(["--model", &config.model_file])
+        .args(["--host", "127.0.0.1"])
+        .args(["--port", &port.to_string()])
+        .args(["--threads", &config.threads.to_string()])
+        .args(["--blasbatchsize", &config.blas_batch_size.to_string()])
+        .args(["--contextsize", &config.context_size.to_string()]);
+
+    if let Some(blasthreads) = config.blasthreads {
+        cmd.args(["--blasthreads", &blasthreads.to_string()]);
+    }
+
+    if let Some(gpu_layers) = config.gpu_layers {
+        cmd.args(["--gpulayers", &gpu_layers.to_string()]);
+    }
+
+    if let Some(rope) = &config.rope_scaling {
+        cmd.args([
+            "--ropeconfig",
+            &rope.scale.to_string(),
+            &rope.base.to_string(),
+        ]);
+    }
+
+    if config.flash_attention {
+        cmd.arg("--flashattention");
+    }
+
+    if config.smart_context {
+        cmd.arg("--smartcontext");
+    }
+
+    if let Some(lora) = &config.lora {
+        cmd.args(["--lora", lora]);
+    }
+
+    if let Some(mmproj) = &config.mmproj {
+        cmd.args(["--mmproj", mmproj]);
+    }
+
+    if let Some(quant_kv) = config.quant_kv {
+        cmd.args(["--quantkv", &quant_kv.to_string()]);
+    }
+
+    cmd.args(&config.custom_args)
+        .current_dir(
+            Path::new(&config.executable_file)
+                .parent()
+                .context("Executable file has no parent directory!")?,
+        )
+        .stderr(log.try_clone()?)
+        .stdout(log)
+        .stdin(std::process::Stdio::null());
+
+    // Don't exit upon ctrl-c.
+    cmd.process_group(0);
+
+    let mut cmd: Command = cmd.into();
+
+    cmd.kill_on_drop(true);
+    unsafe {
+        // Exit when parent process exits.
+        cmd.pre_exec(|| {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGHUP);
+            Ok(())
+        });
+    }
+
+    Ok(cmd.spawn()?)
+}
@@ -0,0 +1,94 @@
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::files::ServerConfig;
+
+/// Number of older logs kept for each instance, besides the current one.
+const KEPT_LOGS: usize = 4;
+
+/// Number of lines shown from a log when an instance fails.
+pub(super) const TAIL_LINES: usize = 20;
+
+/// Returns the directory for logs, which defaults to one belonging to the current user, so that
+/// users sharing a machine don't write to each other's logs.
+fn log_dir(config: &ServerConfig) -> PathBuf {
+    if let Some(dir) = &config.log_dir {
+        return PathBuf::from(dir);
+    }
+
+    let non_empty = |var| env::var_os(var).filter(|value| !value.is_empty());
+
+    if let Some(state) = non_empty("XDG_STATE_HOME") {
+        PathBuf::from(state).join("kobold_cli")
+    } else if let Some(home) = non_empty("HOME") {
+        PathBuf::from(home).join(".local/state/kobold_cli")
+    } else {
+        // SAFETY: getuid has no preconditions and can't fail.
+        let uid = unsafe { libc::getuid() };
+        env::temp_dir().join(format!("kobold_cli_{uid}"))
+    }
+}
+
+/// Returns the name of the log of the `index`th instance spawned for `pool`. Unlike ports, which
+/// may be picked anew every session, this stays the same as long as the pool does.
+pub(super) fn log_name(pool: &str, index: usize) -> String {
+    let pool: String = pool
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    format!("{pool}_{index}")
+}
+
+/// Returns the path of the log named `name`.
+pub(super) fn log_path(config: &ServerConfig, name: &str) -> PathBuf {
+    log_dir(config).join(format!("koboldcpp_{name}.log"))
+}
+
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{i}"));
+    path.into()
+}
+
+/// Moves `path` to `path.1`, `path.1` to `path.2` and so on, then creates a fresh log at `path`.
+pub(super) fn rotate(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create log directory {}", dir.display()))?;
+    }
+
+    for i in (1..KEPT_LOGS).rev() {
+        let _ = fs::rename(numbered(path, i), numbered(path, i + 1));
+    }
+    let _ = fs::rename(path, numbered(path, 1));
+
+    File::create(path).with_context(|| format!("Could not create log file {}", path.display()))
+}
+
+/// Returns the last `n` lines of a log, formatted for showing to the user.
+pub(super) fn tail(path: &Path, n: usize) -> String {
+    let Ok(contents) = fs::read(path) else {
+        return format!("(Could not read {})", path.display());
+    };
+    let contents = String::from_utf8_lossy(&contents);
+    let lines: Vec<&str> = contents.lines().collect();
+
+    let mut out = format!("Last lines of {}:", path.display());
+
+    for line in &lines[lines.len().saturating_sub(n)..] {
+        out.push_str("\n    ");
+        out.push_str(line);
+    }
+
+    out
+}
@@ -0,0 +1,379 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use super::instance::AbortOnDrop;
+
+/// The context size reported by every `MockServer`.
+pub const MOCK_CONTEXT_LENGTH: usize = 2048;
+
+/// The output of a `MockServer`.
+#[derive(Clone, Debug, Default)]
+pub struct MockScript {
+    /// Responses to successive generate requests. The last one is repeated once they run out.
+    pub responses: Vec<String>,
+    /// Time taken to generate each word of a response.
+    pub token_delay: Duration,
+    /// Number of generate requests to fail with a server error before responding normally.
+    pub failures: usize,
+    /// Whether to ignore abort requests, like a server which is stuck.
+    pub ignore_abort: bool,
+    /// Whether to serve streamed generate requests. Without it, generations are polled.
+    pub stream: bool,
+}
+
+impl MockScript {
+    /// Parses `[--port <port>] [--delay <ms>] [--fail <count>] [--stream] [<response>...]`,
+    /// returning the script and the port, if one was given.
+    pub fn from_args(args: &[String]) -> Result<(MockScript, Option<u16>)> {
+        let mut script = MockScript::default();
+        let mut port = None;
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--port" => {
+                    let value = args.next().context("\"--port\" requires a value")?;
+                    port = Some(value.parse().context("Invalid port")?);
+                }
+                "--delay" => {
+                    let value = args.next().context("\"--delay\" requires a value")?;
+                    script.token_delay =
+                        Duration::from_millis(value.parse().context("Invalid delay")?);
+                }
+                "--fail" => {
+                    let value = args.next().context("\"--fail\" requires a value")?;
+                    script.failures = value.parse().context("Invalid failure count")?;
+                }
+                "--stream" => script.stream = true,
+                flag if flag.starts_with("--") => bail!("Unrecognized option: {flag:?}"),
+                response => script.responses.push(response.to_string()),
+            }
+        }
+
+        Ok((script, port))
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    script: MockScript,
+    next_response: usize,
+    /// Bodies of the generate requests received, in order.
+    requests: Vec<Value>,
+    /// Text generated so far by the running request.
+    current: String,
+    aborted: bool,
+    /// Words generated by the last request.
+    last_words: usize,
+    /// The number of generate requests which were streamed.
+    streamed: usize,
+}
+
+/// A fake KoboldCpp server which generates scripted responses, for testing without a model.
+/// Only generate, stream, check, abort, tokenize and info requests are served; everything else is
+/// a 404.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    _task: AbortOnDrop,
+}
+
+impl MockServer {
+    /// Starts serving `script` on `port`, or on a free port if `port` is 0. The server stops
+    /// when dropped.
+    pub async fn start(script: MockScript, port: u16) -> Result<MockServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            script,
+            ..MockState::default()
+        }));
+
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(stream, state.clone()));
+                }
+            }
+        });
+
+        Ok(MockServer {
+            addr,
+            state,
+            _task: AbortOnDrop(task),
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Returns the bodies of every generate request received so far.
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the prompts of every generate request received so far.
+    pub fn prompts(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|body| body["prompt"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    /// Returns the number of generate requests which were streamed.
+    pub fn streamed(&self) -> usize {
+        self.state.lock().unwrap().streamed
+    }
+}
+
+/// Serves a single request, then closes the connection.
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<MockState>>) -> Result<()> {
+    let mut buf = Vec::new();
+
+    let header_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let path = head
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or_default()
+        .to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+
+    while buf.len() < header_end + content_length {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let body: Value = serde_json::from_slice(&buf[header_end..]).unwrap_or(Value::Null);
+
+    if path == "/api/extra/generate/stream" && state.lock().unwrap().script.stream {
+        return stream_generate(&mut stream, &state, &body).await;
+    }
+
+    let (status, response) = match path.as_str() {
+        "/api/v1/generate" => match generate(&state, &body).await {
+            Some(response) => ("200 OK", response),
+            None => (
+                "500 Internal Server Error",
+                json!({ "detail": "Scripted failure" }),
+            ),
+        },
+        "/api/extra/generate/check" => {
+            let text = state.lock().unwrap().current.clone();
+            ("200 OK", json!({ "results": [{ "text": text }] }))
+        }
+        "/api/extra/abort" => {
+            let mut state = state.lock().unwrap();
+            state.aborted = !state.script.ignore_abort;
+            ("200 OK", json!({ "success": true }))
+        }
+        "/api/v1/info/version" | "/api/extra/version" => (
+            "200 OK",
+            json!({ "result": "KoboldCpp", "version": "mock" }),
+        ),
+        "/api/extra/tokencount" => {
+            // Every word is one token.
+            let prompt = body
+                .get("prompt")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            let ids: Vec<usize> = (0..prompt.split_whitespace().count()).collect();
+            ("200 OK", json!({ "value": ids.len(), "ids": ids }))
+        }
+        "/api/v1/model" => ("200 OK", json!({ "result": "koboldcpp/mock" })),
+        "/api/extra/true_max_context_length" => ("200 OK", json!({ "value": MOCK_CONTEXT_LENGTH })),
+        "/api/extra/perf" => {
+            let state = state.lock().unwrap();
+            let delay = state.script.token_delay.as_secs_f64();
+            (
+                "200 OK",
+                json!({
+                    "last_token_count": state.last_words,
+                    "last_eval": state.last_words as f64 * delay,
+                    "total_gens": state.requests.len(),
+                    "queue": 0,
+                }),
+            )
+        }
+        _ => ("404 Not Found", json!({ "detail": "Not found" })),
+    };
+
+    respond(&mut stream, status, response).await
+}
+
+/// Writes a json response, then closes the connection.
+async fn respond(stream: &mut TcpStream, status: &str, response: Value) -> Result<()> {
+    let response = response.to_string();
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Serves a generate request as server-sent events, with one event for each word.
+async fn stream_generate(
+    stream: &mut TcpStream,
+    state: &Mutex<MockState>,
+    body: &Value,
+) -> Result<()> {
+    state.lock().unwrap().streamed += 1;
+
+    let Some(response) = start_generation(state, body) else {
+        let failure = json!({ "detail": "Scripted failure" });
+        return respond(stream, "500 Internal Server Error", failure).await;
+    };
+
+    stream.set_nodelay(true)?;
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+
+    let (sender, mut words) = mpsc::unbounded_channel::<String>();
+
+    let write = async {
+        while let Some(word) = words.recv().await {
+            let event = format!("event: message\ndata: {}\n\n", json!({ "token": word }));
+            // The blank line is sent separately, so that events can arrive split across chunks.
+            let (data, end) = event.split_at(event.len() - 1);
+            stream.write_all(data.as_bytes()).await?;
+            stream.write_all(end.as_bytes()).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let (_, written) = tokio::join!(generate_words(state, body, &response, Some(sender)), write);
+    written?;
+
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Generates the next scripted response. Returns `None` for requests which are scripted to fail.
+async fn generate(state: &Mutex<MockState>, body: &Value) -> Option<Value> {
+    let response = start_generation(state, body)?;
+    Some(generate_words(state, body, &response, None).await)
+}
+
+/// Records a generate request, and returns the response to generate, or `None` if the request
+/// is scripted to fail.
+fn start_generation(state: &Mutex<MockState>, body: &Value) -> Option<String> {
+    let mut state = state.lock().unwrap();
+    state.requests.push(body.clone());
+
+    if state.script.failures > 0 {
+        state.script.failures -= 1;
+        return None;
+    }
+
+    let i = state
+        .next_response
+        .min(state.script.responses.len().saturating_sub(1));
+    let response = state.script.responses.get(i).cloned().unwrap_or_default();
+    state.next_response += 1;
+    state.current.clear();
+    state.aborted = false;
+
+    Some(response)
+}
+
+/// Generates `response` one word at a time, stopping early on abort or after a stop sequence.
+/// Each piece of generated text is sent to `words`, and generation stops once nobody receives
+/// it.
+async fn generate_words(
+    state: &Mutex<MockState>,
+    body: &Value,
+    response: &str,
+    words: Option<mpsc::UnboundedSender<String>>,
+) -> Value {
+    let stop_sequences: Vec<String> = body
+        .get("stop_sequence")
+        .and_then(|stop| serde_json::from_value(stop.clone()).ok())
+        .unwrap_or_default();
+    let delay = state.lock().unwrap().script.token_delay;
+
+    for word in response.split_inclusive([' ', '\n']) {
+        tokio::time::sleep(delay).await;
+
+        let mut state = state.lock().unwrap();
+        if state.aborted {
+            break;
+        }
+
+        let start = state.current.len();
+        state.current.push_str(word);
+
+        let stop = stop_sequences
+            .iter()
+            .filter_map(|stop| Some(state.current.find(stop.as_str())? + stop.len()))
+            .min();
+
+        if let Some(end) = stop {
+            state.current.truncate(end);
+        }
+
+        if let Some(words) = &words {
+            if words.send(state.current[start..].to_string()).is_err() {
+                break;
+            }
+        }
+
+        if stop.is_some() {
+            break;
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    state.last_words = state.current.split_whitespace().count();
+    let text = state.current.clone();
+    json!({ "results": [{ "text": text }] })
+}
+
+/// Runs `kobold_cli mock-server [--port <port>] [--delay <ms>] [--fail <count>] [--stream]
+/// [<response>...]` until Ctrl-C.
+pub async fn run_mock_server(args: &[String]) -> Result<()> {
+    let (script, port) = MockScript::from_args(args)?;
+    let server = MockServer::start(script, port.unwrap_or(5001)).await?;
+
+    println!("Mock Kobold server listening on {}", server.url());
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
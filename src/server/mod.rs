@@ -1,303 +1,531 @@
-use std::io::Write;
-use std::path::Path;
-use std::time::Duration;
-
-use crate::files::{ServerConfig, ServerPrompt};
-use anyhow::{bail, Context, Result};
+mod backend;
+mod instance;
+mod logs;
+pub mod mock;
+mod pools;
+mod preview;
+mod reconcile;
+mod routing;
+mod tokenize;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::files::{BackendKind, ServerConfig, ServerPrompt};
+use anyhow::{anyhow, bail, Result};
 use reqwest::Client;
-use serde_json::Value;
-use tokio::process::{Child, Command};
-
-use std::os::unix::process::CommandExt;
+use tokio::sync::Notify;
+
+use backend::{new_backend, Backend, ServerInfo};
+use instance::{AbortOnDrop, Instance, Lease, Status};
+pub use preview::PreviewSink;
+use preview::{check_actor, stream_request, Preview};
+use routing::{LastPrompt, Router};
+use tokenize::TokenCache;
+
+pub use pools::Servers;
+
+/// An instance's status, and what it reports about itself.
+pub struct InstanceInfo {
+    pub(crate) pool: String,
+    pub(crate) name: String,
+    pub(crate) status: String,
+    pub(crate) info: Result<ServerInfo>,
+}
 
-pub struct Servers {
+/// The instances spawned or attached to for one `ServerConfig`.
+pub struct Pool {
+    name: String,
+    config: ServerConfig,
+    backend: Arc<dyn Backend>,
     client: Client,
-    children: Vec<Child>,
-    urls: Vec<String>,
-    last_prompts: Vec<String>,
+    instances: Vec<Instance>,
+    router: Router,
+    token_cache: TokenCache,
+    /// Background task restarting instances one at a time.
+    rollout: Option<AbortOnDrop>,
     current_server: usize,
 }
 
-fn spawn_server(config: ServerConfig, port: u16) -> Result<Child> {
-    let mut cmd = std::process::Command::new(&config.executable_file);
-
-    cmd.args(["--skiplauncher"])
-        .args(["--model", &config.model_file])
-        .args(["--host", "127.0.0.1"])
-        .args(["--port", &port.to_string()])
-        .args(["--threads", &config.threads.to_string()])
-        .args(["--blasbatchsize", &config.blas_batch_size.to_string()])
-        .args(["--contextsize", &config.context_size.to_string()]);
+/// Adds a scheme to `url` if it has none, and removes any trailing slash.
+fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
 
-    if let Some(blasthreads) = config.blasthreads {
-        cmd.args(["--blasthreads", &blasthreads.to_string()]);
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{url}")
     }
+}
 
-    cmd
-        .args(&config.custom_args)
-        .current_dir(
-            Path::new(&config.executable_file)
-                .parent()
-                .context("Executable file has no parent directory!")?,
-        )
-        .stderr(std::process::Stdio::null())
-        .stdout(std::process::Stdio::null())
-        .stdin(std::process::Stdio::null());
-
-    // Don't exit upon ctrl-c.
-    cmd.process_group(0);
-
-    let mut cmd: Command = cmd.into();
-
-    cmd.kill_on_drop(true);
-    unsafe {
-        // Exit when parent process exits.
-        cmd.pre_exec(|| {
-            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGHUP);
-            Ok(())
-        });
+/// Returns the normalized `urls` of `config`, which must not repeat.
+fn normalized_urls(config: &ServerConfig) -> Result<Vec<String>> {
+    let urls: Vec<String> = config.urls.iter().map(|url| normalize_url(url)).collect();
+
+    if let Some((i, url)) = urls
+        .iter()
+        .enumerate()
+        .find(|(i, url)| urls[..*i].contains(url))
+    {
+        bail!("{url} appears more than once in urls (entry {})!", i + 1);
     }
 
-    Ok(cmd.spawn()?)
+    Ok(urls)
 }
 
-// TODO: consider using token count instead of character count.
-// TODO: consider a time decay for erased character cost.
-fn generation_cost(last_prompt: &str, new_prompt: &str) -> f64 {
-    let shared_prefix_length = last_prompt
-        .as_bytes()
-        .iter()
-        .zip(new_prompt.as_bytes())
-        .position(|(p1, p2)| p1 != p2)
-        .unwrap_or(last_prompt.len().min(new_prompt.len()));
+/// Returns a client which connects with the timeout in `config`.
+fn new_client(config: &ServerConfig) -> Result<Client> {
+    let mut builder = Client::builder();
+
+    if config.timeouts.connect != 0 {
+        builder = builder.connect_timeout(Duration::from_secs(config.timeouts.connect));
+    }
 
-    let parsed = new_prompt.len() - shared_prefix_length;
-    let erased = last_prompt.len() - shared_prefix_length;
+    Ok(builder.build()?)
+}
 
-    parsed as f64 + erased as f64 * 0.3
+/// Checks that `prompt` is valid for the servers of `config`, without contacting them.
+pub fn check_prompt(config: &ServerConfig, prompt: &ServerPrompt) -> Result<()> {
+    prompt.validate()?;
+    new_backend(config).check_prompt(prompt)
 }
 
-impl Servers {
-    pub async fn from_config(config: &ServerConfig) -> Result<Servers> {
-        let mut children = Vec::new();
-        let mut urls = Vec::new();
-        let mut last_prompts = Vec::new();
-        let client = Client::new();
-        let ports = config.port..config.port + config.instances as u16;
-
-        for port in ports {
-            children.push(spawn_server(config.clone(), port)?);
-            urls.push(format!("127.0.0.1:{port}"));
-            last_prompts.push(String::new());
+/// Waits for `gen` for at most `timeout` seconds, stopping the request with `abort` if it takes
+/// longer. A timeout of 0 waits forever.
+async fn with_timeout(
+    gen: impl Future<Output = Result<String>>,
+    abort: impl Future<Output = Result<()>>,
+    timeout: u64,
+) -> Result<String> {
+    if timeout == 0 {
+        return gen.await;
+    }
+
+    match tokio::time::timeout(Duration::from_secs(timeout), gen).await {
+        Ok(out) => out,
+        Err(_) => {
+            let _ = abort.await;
+            bail!("Generation timed out after {timeout} seconds!")
         }
+    }
+}
 
-        if servers_are_online(&client, &urls)
-            .await?
-            .into_iter()
-            .any(|x| x)
-        {
-            bail!("A Kobold server is already running on at least one specified port!");
+impl Pool {
+    fn new(
+        name: &str,
+        backend: Arc<dyn Backend>,
+        client: Client,
+        instances: Vec<Instance>,
+        config: &ServerConfig,
+    ) -> Self {
+        Pool {
+            name: name.to_string(),
+            config: config.clone(),
+            backend,
+            client,
+            instances,
+            router: Router::new(config.routing.clone()),
+            token_cache: TokenCache::default(),
+            rollout: None,
+            current_server: 0,
         }
+    }
 
-        tokio::time::sleep(tokio::time::Duration::from_millis(900)).await;
+    pub async fn from_config(name: &str, config: &ServerConfig) -> Result<Pool> {
+        let backend = new_backend(config);
+        let client = new_client(config)?;
 
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        if !config.urls.is_empty() {
+            return Self::attach(name, backend, client, config).await;
+        }
 
-            if servers_are_online(&client, &urls)
-                .await?
-                .into_iter()
-                .all(|x| x)
-            {
-                break;
-            }
+        if config.backend != BackendKind::Kobold {
+            bail!("Only KoboldCpp servers can be spawned; set \"urls\" to use other backends!");
         }
 
-        Ok(Servers {
-            client,
-            children,
-            urls,
-            last_prompts,
-            current_server: 0,
-        })
-    }
+        config.validate()?;
 
-    fn best_server(&self, prompt: &str) -> usize {
-        self.last_prompts
-            .iter()
-            .map(|last_prompt| generation_cost(last_prompt, prompt))
+        let ports = match config.ports() {
+            Some(ports) => {
+                let urls: Vec<String> = ports.iter().copied().map(instance::local_url).collect();
+
+                if servers_are_online(&*backend, &client, &urls)
+                    .await?
+                    .into_iter()
+                    .any(|x| x)
+                {
+                    bail!("A Kobold server is already running on at least one specified port!");
+                }
+
+                ports
+            }
+            None => {
+                let ports = instance::free_ports(config.instances)?;
+                println!("Using ports {}.", format_ports(&ports));
+                ports
+            }
+        };
+
+        let instances = ports
+            .into_iter()
             .enumerate()
-            .min_by(|(_, x), (_, y)| x.partial_cmp(y).unwrap())
-            .expect("No server instances!")
-            .0
-    }
-}
+            .map(|(i, port)| {
+                let log_name = logs::log_name(name, i);
+                Instance::spawn(backend.clone(), client.clone(), config, log_name, port)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-async fn server_is_online(client: Client, url: String) -> Result<bool> {
-    let res = client
-        .post(&format!("http://{url}/api/v1/info/version"))
-        .header("Content-Length", "0")
-        .send()
-        .await;
-
-    match res {
-        Ok(_) => Ok(true),
-        Err(e) if e.is_connect() || e.is_request() => Ok(false),
-        Err(e) => Err(e.into()),
+        tokio::time::sleep(tokio::time::Duration::from_millis(900)).await;
+        wait_until_ready(
+            &instances.iter().collect::<Vec<_>>(),
+            config.startup_timeout,
+        )
+        .await?;
+
+        Ok(Self::new(name, backend, client, instances, config))
     }
-}
 
-async fn servers_are_online(client: &Client, urls: &[String]) -> Result<Vec<bool>> {
-    let handles = urls
-        .iter()
-        .map(|url| tokio::spawn(server_is_online(client.clone(), url.clone())))
-        .collect::<Vec<_>>();
+    /// Connects to the already running servers in `config.urls`. Servers which are offline are
+    /// left out of routing until they come online.
+    async fn attach(
+        name: &str,
+        backend: Arc<dyn Backend>,
+        client: Client,
+        config: &ServerConfig,
+    ) -> Result<Pool> {
+        let urls = normalized_urls(config)?;
+        let online = servers_are_online(&*backend, &client, &urls).await?;
+
+        if !online.iter().any(|x| *x) {
+            bail!("Could not connect to servers: {}", urls.join(", "));
+        }
 
-    let mut out = Vec::new();
+        let instances: Vec<Instance> = urls
+            .into_iter()
+            .zip(online)
+            .map(|(url, online)| Instance::attach(backend.clone(), client.clone(), url, online))
+            .collect();
+
+        for instance in instances.iter().filter(|inst| !inst.is_ready()) {
+            println!(
+                "{} is offline, and won't be used until it responds.",
+                instance.name()
+            );
+        }
 
-    for handle in handles {
-        out.push(handle.await??);
+        Ok(Self::new(name, backend, client, instances, config))
     }
 
-    Ok(out)
-}
+    /// Checks that `prompt` is valid for this pool's servers.
+    fn check_prompt(&self, prompt: &ServerPrompt) -> Result<()> {
+        prompt.validate()?;
+        self.backend.check_prompt(prompt)
+    }
 
-async fn check_request(client: &Client, url: &str) -> Result<String> {
-    let json: serde_json::Value = client
-        .post(&format!("http://{url}/api/extra/generate/check"))
-        .header("Content-Length", "0")
-        .send()
-        .await?
-        .json()
-        .await?;
+    /// Returns the number of tokens in `text`, or `None` if no server can tokenize it.
+    async fn count_tokens(&mut self, text: &str) -> Option<usize> {
+        let url = &self.instances.iter().find(|inst| inst.is_ready())?.url;
 
-    let Some(s) = json.pointer("/results/0/text").and_then(Value::as_str) else {
-        bail!("Received invalid json: {json:?}");
-    };
+        let tokens = self
+            .token_cache
+            .tokenize(&*self.backend, &self.client, url, text)
+            .await;
 
-    Ok(s.to_string())
-}
-
-async fn generate_request(client: Client, url: String, prompt: ServerPrompt) -> Result<String> {
-    let json: serde_json::Value = client
-        .post(format!("http://{url}/api/v1/generate"))
-        .json(&prompt)
-        .send()
-        .await?
-        .json()
-        .await?;
+        tokens.map(|tokens| tokens.len())
+    }
 
-    let Some(s) = json.pointer("/results/0/text").and_then(Value::as_str) else {
-        bail!("Received invalid json: {json:?}");
-    };
+    /// Picks a ready instance to process `prompt` for `chat`, records `prompt` as its last
+    /// prompt, and leases it until the request is done.
+    async fn route(&mut self, chat: &str, prompt: &str) -> Result<(usize, Lease)> {
+        for instance in &mut self.instances {
+            instance.forget_lost_cache();
+        }
 
-    Ok(s.to_string())
+        let Some(url) = self
+            .instances
+            .iter()
+            .find(|inst| inst.is_ready())
+            .map(|inst| &inst.url)
+        else {
+            bail!("No server instances are online!");
+        };
+
+        let tokens = self
+            .token_cache
+            .tokenize(&*self.backend, &self.client, url, prompt)
+            .await;
+
+        let Some(best_server) = self
+            .router
+            .route(chat, prompt, tokens.as_deref(), &self.instances)
+        else {
+            bail!("No server instances are online!");
+        };
+
+        let instance = &mut self.instances[best_server];
+
+        instance.last_prompt = LastPrompt {
+            text: prompt.to_string(),
+            tokens,
+            time: Instant::now(),
+        };
+
+        Ok((best_server, instance.lease()))
+    }
 }
 
-async fn abort_request(client: Client, url: String) -> Result<()> {
-    client
-        .post(&format!("http://{url}/api/extra/abort"))
-        .header("Content-Length", "0")
-        .send()
-        .await?;
+impl Pool {
+    /// Queries every instance for information about its model and performance.
+    async fn info(&self) -> Result<Vec<InstanceInfo>> {
+        let handles: Vec<_> = self
+            .instances
+            .iter()
+            .map(|inst| tokio::spawn(self.backend.info(self.client.clone(), inst.url.clone())))
+            .collect();
+
+        let mut out = Vec::new();
+
+        for (inst, handle) in self.instances.iter().zip(handles) {
+            out.push(InstanceInfo {
+                pool: self.name.clone(),
+                name: inst.name(),
+                status: inst.status.get().to_string(),
+                info: handle.await?,
+            });
+        }
 
-    Ok(())
+        Ok(out)
+    }
 }
 
-async fn check_actor(
-    client: Client,
-    url: String,
-    mut abort: tokio::sync::mpsc::Receiver<()>,
-    interval: Duration,
-) -> Result<()> {
-    let mut check_len = 0;
+fn format_ports(ports: &[u16]) -> String {
+    ports
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Waits until every instance is online, failing if one exits or takes longer than `timeout`
+/// seconds. A timeout of 0 waits forever.
+async fn wait_until_ready(instances: &[&Instance], timeout: u64) -> Result<()> {
+    let start = Instant::now();
 
     loop {
-        tokio::time::sleep(interval).await;
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 
-        if abort.try_recv().is_ok() {
-            break;
+        if let Some(failed) = instances
+            .iter()
+            .find(|inst| inst.status.get() == Status::Failed)
+        {
+            bail!(
+                "{} failed to start!\n{}",
+                failed.name(),
+                failed.log_tail().unwrap_or_default()
+            );
         }
 
-        let check = check_request(&client, &url).await?;
-
-        if check_len > check.len() {
-            break;
+        // Statuses change in the background, so the pending instance is found in the same check
+        // which decides whether all are ready.
+        let Some(pending) = instances.iter().find(|inst| !inst.is_ready()) else {
+            return Ok(());
+        };
+
+        if timeout != 0 && start.elapsed() > Duration::from_secs(timeout) {
+            bail!(
+                "{} did not come online within {timeout} seconds!\n{}",
+                pending.name(),
+                pending.log_tail().unwrap_or_default()
+            );
         }
-        print!("{}", &check[check_len..]);
-        std::io::stdout().flush()?;
-        check_len = check.len();
+    }
+}
+
+async fn servers_are_online(
+    backend: &dyn Backend,
+    client: &Client,
+    urls: &[String],
+) -> Result<Vec<bool>> {
+    let handles = urls
+        .iter()
+        .map(|url| tokio::spawn(backend.health(client.clone(), url.clone())))
+        .collect::<Vec<_>>();
+
+    let mut out = Vec::new();
+
+    for handle in handles {
+        out.push(handle.await??);
     }
 
-    Ok(())
+    Ok(out)
 }
 
 use std::future::Future;
 
-impl Servers {
-    pub fn generate(
+/// Stops a running request, by notifying `cancel` and aborting it on the server.
+async fn abort_request(
+    backend: Arc<dyn Backend>,
+    client: Client,
+    url: String,
+    cancel: Arc<Notify>,
+) -> Result<()> {
+    cancel.notify_one();
+    backend.abort(client, url).await
+}
+
+impl Pool {
+    /// Starts generating on the best instance for `chat`, which identifies a conversation whose
+    /// prompts share a prefix.
+    pub async fn generate(
         &mut self,
+        chat: &str,
         prompt: ServerPrompt,
-    ) -> (
+    ) -> Result<(
         impl Future<Output = Result<String>>,
         impl Future<Output = Result<()>>,
-    ) {
-        let best_server = self.best_server(&prompt.prompt);
+    )> {
+        let (best_server, lease) = self.route(chat, &prompt.prompt).await?;
         let client = self.client.clone();
-        let url = self.urls[best_server].clone();
-        self.last_prompts[best_server] = prompt.prompt.clone();
+        let url = self.instances[best_server].url.clone();
+        let gen = self.backend.generate(client.clone(), url.clone(), prompt);
+        let abort_on_timeout = self.backend.abort(client.clone(), url.clone());
+        let timeout = self.config.timeouts.total;
+        let cancel = Arc::new(Notify::new());
+        let can_abort = self.backend.can_abort();
+
+        let gen = {
+            let (url, cancel) = (url.clone(), cancel.clone());
+            async move {
+                let gen = async {
+                    tokio::select! {
+                        out = gen => out,
+                        _ = cancel.notified(), if !can_abort => {
+                            bail!("Stopped, and {url} can't return partial responses!")
+                        }
+                    }
+                };
+                let out = with_timeout(gen, abort_on_timeout, timeout).await;
+                lease.report(&out);
+                out
+            }
+        };
 
-        (
-            generate_request(client.clone(), url.clone(), prompt),
-            abort_request(client, url),
-        )
+        Ok((
+            gen,
+            abort_request(self.backend.clone(), client, url, cancel),
+        ))
     }
 
-    pub fn generate_with_preview(
+    pub async fn generate_with_preview(
         &mut self,
+        chat: &str,
         prompt: ServerPrompt,
         check_interval: Duration,
-    ) -> (
+        sink: PreviewSink,
+    ) -> Result<(
         impl Future<Output = Result<String>>,
         impl Future<Output = Result<()>>,
-    ) {
-        use tokio::sync::mpsc::*;
-
-        async fn abort(client: Client, url: String, stop_check: Sender<()>) -> Result<()> {
-            let _ = stop_check.send(()).await;
-            abort_request(client, url).await
-        }
-
-        async fn generate(
+    )> {
+        /// Generates while polling the server for a preview, for servers which can't stream.
+        async fn poll_request(
+            backend: Arc<dyn Backend>,
             client: Client,
             url: String,
             prompt: ServerPrompt,
-            stop_check: Sender<()>,
+            cancel: Arc<Notify>,
+            check_interval: Duration,
+            sink: PreviewSink,
         ) -> Result<String> {
-            let out = generate_request(client, url, prompt).await;
-            let _ = stop_check.send(()).await;
+            let (stop_check, recv) = tokio::sync::oneshot::channel();
+            let checker = tokio::spawn(check_actor(
+                backend.clone(),
+                client.clone(),
+                url.clone(),
+                recv,
+                check_interval,
+                Preview::new(prompt.stop_sequence.clone(), sink),
+            ));
+
+            let can_abort = backend.can_abort();
+            let gen = backend.generate(client, url.clone(), prompt);
+            let out = tokio::select! {
+                out = gen => out,
+                _ = cancel.notified(), if !can_abort => {
+                    Err(anyhow!("Stopped, and {url} can't return partial responses!"))
+                }
+            };
+            let _ = stop_check.send(());
+            let mut preview = checker.await?;
+
+            if let Ok(text) = &out {
+                preview.update(text)?;
+            }
+            preview.finish()?;
+
             out
         }
 
-        let best_server = self.best_server(&prompt.prompt);
+        let (best_server, lease) = self.route(chat, &prompt.prompt).await?;
         let client = self.client.clone();
-        let url = self.urls[best_server].clone();
-        let (send, recv) = channel(1);
-        self.last_prompts[best_server] = prompt.prompt.clone();
-
-        tokio::spawn(check_actor(
-            client.clone(),
-            url.clone(),
-            recv,
-            check_interval,
-        ));
-
-        print!("{}", prompt.prompt);
-        std::io::stdout().flush().unwrap();
-
-        (
-            generate(client.clone(), url.clone(), prompt, send.clone()),
-            abort(client, url, send),
-        )
+        let url = self.instances[best_server].url.clone();
+        let cancel = Arc::new(Notify::new());
+        let backend = self.backend.clone();
+        let timeouts = self.config.timeouts.clone();
+
+        sink.start(&prompt.prompt)?;
+
+        let gen = {
+            let (client, url, cancel) = (client.clone(), url.clone(), cancel.clone());
+            async move {
+                let gen = async {
+                    let streamed = stream_request(
+                        &*backend,
+                        &client,
+                        &url,
+                        &prompt,
+                        cancel.clone(),
+                        timeouts.idle,
+                        sink.clone(),
+                    )
+                    .await;
+
+                    match streamed.transpose() {
+                        Some(streamed) => {
+                            lease.report(&streamed);
+                            streamed
+                        }
+                        // The server doesn't support streaming, so poll it instead.
+                        None => {
+                            let polled = poll_request(
+                                backend.clone(),
+                                client.clone(),
+                                url.clone(),
+                                prompt.clone(),
+                                cancel.clone(),
+                                check_interval,
+                                sink.clone(),
+                            )
+                            .await;
+                            lease.report(&polled);
+                            polled
+                        }
+                    }
+                };
+                let abort_on_timeout =
+                    abort_request(backend.clone(), client.clone(), url.clone(), cancel.clone());
+                let out = with_timeout(gen, abort_on_timeout, timeouts.total).await;
+
+                // Requests which timed out never reported their result.
+                if out.is_err() {
+                    lease.report(&out);
+                }
+                out
+            }
+        };
+
+        Ok((
+            gen,
+            abort_request(self.backend.clone(), client, url, cancel),
+        ))
     }
 }
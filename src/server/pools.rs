@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use super::*;
+use anyhow::Context;
+
+/// Every pool of servers used by a prompt file, by name.
+#[derive(Default)]
+pub struct Servers {
+    pools: BTreeMap<String, Pool>,
+}
+
+impl Servers {
+    pub async fn from_configs(configs: &BTreeMap<String, ServerConfig>) -> Result<Servers> {
+        let mut pools = BTreeMap::new();
+
+        for (name, config) in configs {
+            let pool = Pool::from_config(name, config)
+                .await
+                .with_context(|| format!("Failed to start pool \"{name}\""))?;
+            pools.insert(name.clone(), pool);
+        }
+
+        Ok(Servers { pools })
+    }
+
+    /// Applies `configs`, reconciling pools which still exist, starting pools which were added
+    /// and stopping ones which were removed.
+    pub async fn reconcile(&mut self, configs: &BTreeMap<String, ServerConfig>) -> Result<()> {
+        // Stop removed pools first, so that their ports are free.
+        self.pools.retain(|name, pool| {
+            configs
+                .get(name)
+                .is_some_and(|config| pool.can_reconcile(config))
+        });
+
+        for (name, config) in configs {
+            match self.pools.get_mut(name) {
+                Some(pool) if pool.config == *config => {}
+                Some(pool) => pool
+                    .reconcile(config)
+                    .await
+                    .with_context(|| format!("Failed to update pool \"{name}\""))?,
+                None => {
+                    let pool = Pool::from_config(name, config)
+                        .await
+                        .with_context(|| format!("Failed to start pool \"{name}\""))?;
+                    self.pools.insert(name.clone(), pool);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Queries every instance of every pool for information about its model and performance.
+    pub async fn info(&self) -> Result<Vec<InstanceInfo>> {
+        let mut out = Vec::new();
+
+        for pool in self.pools.values() {
+            out.extend(pool.info().await?);
+        }
+
+        Ok(out)
+    }
+
+    /// Checks that `prompt` is valid for the servers of `pool`.
+    pub fn check_prompt(&mut self, pool: &str, prompt: &ServerPrompt) -> Result<()> {
+        self.pool(pool)?.check_prompt(prompt)
+    }
+
+    /// Counts the tokens in `text` with the tokenizer of `pool`, or returns `None` if none of
+    /// its servers can tokenize it.
+    pub async fn count_tokens(&mut self, pool: &str, text: &str) -> Result<Option<usize>> {
+        Ok(self.pool(pool)?.count_tokens(text).await)
+    }
+
+    fn pool(&mut self, name: &str) -> Result<&mut Pool> {
+        self.pools
+            .get_mut(name)
+            .with_context(|| format!("No server pool named \"{name}\"!"))
+    }
+
+    /// Starts generating on the best instance in `pool`. See `Pool::generate`.
+    pub async fn generate(
+        &mut self,
+        pool: &str,
+        chat: &str,
+        prompt: ServerPrompt,
+    ) -> Result<(
+        impl Future<Output = Result<String>>,
+        impl Future<Output = Result<()>>,
+    )> {
+        self.pool(pool)?.generate(chat, prompt).await
+    }
+
+    /// Starts generating on the best instance in `pool` while printing a preview. See
+    /// `Pool::generate_with_preview`.
+    pub async fn generate_with_preview(
+        &mut self,
+        pool: &str,
+        chat: &str,
+        prompt: ServerPrompt,
+        check_interval: Duration,
+        sink: PreviewSink,
+    ) -> Result<(
+        impl Future<Output = Result<String>>,
+        impl Future<Output = Result<()>>,
+    )> {
+        self.pool(pool)?
+            .generate_with_preview(chat, prompt, check_interval, sink)
+            .await
+    }
+}
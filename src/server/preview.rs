@@ -0,0 +1,300 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use reqwest::Client;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use super::backend::Backend;
+use crate::files::ServerPrompt;
+
+/// Where the preview of a generation is shown.
+#[derive(Clone, Debug, Default)]
+pub enum PreviewSink {
+    /// Prints the prompt, then the generated text as it arrives.
+    #[default]
+    Stdout,
+    /// Sends the generated text as it arrives.
+    Channel(mpsc::UnboundedSender<String>),
+}
+
+impl PreviewSink {
+    /// Shows the prompt which is being generated from.
+    pub(super) fn start(&self, prompt: &str) -> Result<()> {
+        if let Self::Stdout = self {
+            print!("{prompt}");
+            std::io::stdout().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Shows newly generated text.
+    fn write(&self, text: &str) -> Result<()> {
+        match self {
+            Self::Stdout => {
+                print!("{text}");
+                std::io::stdout().flush()?;
+            }
+            // Nobody is listening anymore, which doesn't stop the generation.
+            Self::Channel(sender) => {
+                let _ = sender.send(text.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shows generated text as it arrives, holding back anything that could be the start of a stop
+/// sequence.
+#[derive(Debug)]
+pub(super) struct Preview {
+    text: String,
+    printed: usize,
+    stop_sequences: Vec<String>,
+    stopped: bool,
+    sink: PreviewSink,
+}
+
+impl Preview {
+    pub(super) fn new(stop_sequences: Vec<String>, sink: PreviewSink) -> Self {
+        Self {
+            text: String::new(),
+            printed: 0,
+            // An empty stop sequence would match before any text, and stop every generation.
+            stop_sequences: stop_sequences
+                .into_iter()
+                .filter(|stop| !stop.is_empty())
+                .collect(),
+            stopped: false,
+            sink,
+        }
+    }
+
+    /// Returns the length of the longest suffix of the text which is a prefix of a stop sequence.
+    fn held_back(&self) -> usize {
+        self.stop_sequences
+            .iter()
+            .flat_map(|stop| stop.char_indices().map(|(i, _)| &stop[..i]).skip(1))
+            .filter(|prefix| self.text.ends_with(prefix))
+            .map(str::len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn print_to(&mut self, end: usize) -> Result<()> {
+        if end > self.printed {
+            self.sink.write(&self.text[self.printed..end])?;
+            self.printed = end;
+        }
+        Ok(())
+    }
+
+    /// Appends `token` to the text and prints as much as possible. Returns true once a stop
+    /// sequence has been generated, after which the text ends with that stop sequence.
+    pub(super) fn push(&mut self, token: &str) -> Result<bool> {
+        if self.stopped {
+            return Ok(true);
+        }
+
+        self.text.push_str(token);
+
+        let stop = self
+            .stop_sequences
+            .iter()
+            .filter_map(|stop| Some((self.text[self.printed..].find(stop)?, stop.len())))
+            .min();
+
+        if let Some((i, len)) = stop {
+            let i = self.printed + i;
+            self.print_to(i)?;
+            self.text.truncate(i + len);
+            self.stopped = true;
+            return Ok(true);
+        }
+
+        self.print_to(self.text.len() - self.held_back())?;
+        Ok(false)
+    }
+
+    /// Updates the preview with the full text generated so far, as returned by polling. Text
+    /// which doesn't extend what has already been seen is ignored.
+    pub(super) fn update(&mut self, text: &str) -> Result<bool> {
+        // Incomplete UTF-8 characters at the end are sent as replacement characters.
+        let text = text.trim_end_matches(char::REPLACEMENT_CHARACTER);
+
+        match text.strip_prefix(&self.text[..]) {
+            Some(rest) if !rest.is_empty() => {
+                let rest = rest.to_string();
+                self.push(&rest)
+            }
+            _ => Ok(self.stopped),
+        }
+    }
+
+    /// Prints any text that was held back, and returns the full text.
+    pub(super) fn finish(mut self) -> Result<String> {
+        if !self.stopped {
+            self.print_to(self.text.len())?;
+        }
+        Ok(self.text)
+    }
+}
+
+/// Generates text with the backend's server-sent events endpoint, previewing tokens as they
+/// arrive, until the stream ends or `cancel` is notified. Fails if no data arrives for
+/// `idle_timeout` seconds, unless it is 0. Returns `None` if the server doesn't support
+/// streaming.
+pub(super) async fn stream_request(
+    backend: &dyn Backend,
+    client: &Client,
+    url: &str,
+    prompt: &ServerPrompt,
+    cancel: Arc<Notify>,
+    idle_timeout: u64,
+    sink: PreviewSink,
+) -> Result<Option<String>> {
+    let Some(mut res) = backend
+        .stream(client.clone(), url.to_string(), prompt.clone())
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let mut preview = Preview::new(prompt.stop_sequence.clone(), sink);
+    let mut buf = Vec::new();
+
+    'outer: loop {
+        let idle = async {
+            if idle_timeout == 0 {
+                std::future::pending().await
+            } else {
+                tokio::time::sleep(Duration::from_secs(idle_timeout)).await
+            }
+        };
+
+        let chunk = tokio::select! {
+            chunk = res.chunk() => chunk?,
+            _ = cancel.notified() => break,
+            _ = idle => {
+                let _ = backend.abort(client.clone(), url.to_string()).await;
+                bail!("{url} sent nothing for {idle_timeout} seconds!");
+            }
+        };
+
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        buf.extend_from_slice(&chunk);
+
+        // Events end with an empty line, so complete events are always valid UTF-8.
+        while let Some(end) = find_event_end(&buf) {
+            let event: Vec<u8> = buf.drain(..end).collect();
+
+            for line in String::from_utf8_lossy(&event).lines() {
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+
+                let Some(token) = backend.parse_stream_data(data.trim())? else {
+                    continue;
+                };
+
+                if preview.push(&token)? {
+                    backend.abort(client.clone(), url.to_string()).await?;
+                    break 'outer;
+                }
+            }
+        }
+    }
+
+    Ok(Some(preview.finish()?))
+}
+
+/// Returns the index just past the first blank line in `buf`.
+fn find_event_end(buf: &[u8]) -> Option<usize> {
+    (0..buf.len()).find_map(|i| {
+        if buf[i..].starts_with(b"\n\n") {
+            Some(i + 2)
+        } else if buf[i..].starts_with(b"\r\n\r\n") {
+            Some(i + 4)
+        } else {
+            None
+        }
+    })
+}
+
+/// Polls the server for generated text until `stop` is signaled or the server can't report it.
+pub(super) async fn check_actor(
+    backend: Arc<dyn Backend>,
+    client: Client,
+    url: String,
+    mut stop: oneshot::Receiver<()>,
+    interval: Duration,
+    mut preview: Preview,
+) -> Preview {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        if stop.try_recv().is_ok() {
+            break;
+        }
+
+        let Ok(Some(check)) = backend.check(client.clone(), url.clone()).await else {
+            break;
+        };
+
+        if !matches!(preview.update(&check), Ok(false)) {
+            break;
+        }
+    }
+
+    preview
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_ends_are_found() {
+        assert_eq!(find_event_end(b"data: a\n\ndata: b\n\n"), Some(9));
+        assert_eq!(find_event_end(b"data: a\r\n\r\n"), Some(11));
+        assert_eq!(find_event_end(b"data: a\n"), None);
+        assert_eq!(find_event_end(b""), None);
+    }
+
+    #[test]
+    fn possible_stop_sequences_are_held_back() {
+        let (sender, mut previews) = mpsc::unbounded_channel();
+        let mut preview = Preview::new(vec!["\nUser:".into()], PreviewSink::Channel(sender));
+
+        assert!(!preview.push(" Hi.\n").unwrap());
+        assert_eq!(previews.try_recv().unwrap(), " Hi.");
+
+        // Text which turns out not to be a stop sequence is shown once that's clear.
+        assert!(!preview.push("Us").unwrap());
+        assert!(!preview.push("ed").unwrap());
+        assert_eq!(previews.try_recv().unwrap(), "\nUsed");
+
+        assert!(!preview.push("\nUs").unwrap());
+        assert!(preview.push("er: more").unwrap());
+        assert!(previews.try_recv().is_err());
+        assert_eq!(preview.finish().unwrap(), " Hi.\nUsed\nUser:");
+    }
+
+    #[test]
+    fn empty_stop_sequences_are_ignored() {
+        let (sender, mut previews) = mpsc::unbounded_channel();
+        let stop_sequences = vec![String::new(), "\nUser:".into()];
+        let mut preview = Preview::new(stop_sequences, PreviewSink::Channel(sender));
+
+        assert!(!preview.push(" Hi.").unwrap());
+        assert_eq!(previews.try_recv().unwrap(), " Hi.");
+        assert!(!preview.update(" Hi. Bye.").unwrap());
+        assert_eq!(previews.try_recv().unwrap(), " Bye.");
+        assert!(preview.push("\nUser:").unwrap());
+        assert_eq!(preview.finish().unwrap(), " Hi. Bye.\nUser:");
+    }
+}
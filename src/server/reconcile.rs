@@ -0,0 +1,195 @@
+use super::instance::InstanceHandle;
+use super::*;
+
+impl Pool {
+    /// Returns whether `config` can be applied by `reconcile`, rather than by starting over.
+    pub fn can_reconcile(&self, config: &ServerConfig) -> bool {
+        config.backend == self.config.backend
+            && config.urls.is_empty() == self.config.urls.is_empty()
+    }
+
+    /// Applies `config` to the running servers, only restarting instances whose settings
+    /// changed, spawning instances which were added and stopping ones which were removed.
+    pub async fn reconcile(&mut self, config: &ServerConfig) -> Result<()> {
+        self.rollout = None;
+        self.backend = new_backend(config);
+        self.client = new_client(config)?;
+
+        if config.urls.is_empty() {
+            self.reconcile_instances(config).await?;
+        } else {
+            self.reconcile_urls(config).await?;
+        }
+
+        // Instance indices may have changed, so chats need new homes.
+        self.router = Router::new(config.routing.clone());
+        self.config = config.clone();
+        Ok(())
+    }
+
+    async fn reconcile_instances(&mut self, config: &ServerConfig) -> Result<()> {
+        config.validate()?;
+
+        let ports: Vec<u16> = match config.ports() {
+            Some(ports) => ports,
+            None => {
+                // Keep the ports which were already picked, and pick more if needed.
+                let mut ports: Vec<u16> = self
+                    .instances
+                    .iter()
+                    .filter(|inst| inst.status.get() != Status::Failed)
+                    .filter_map(|inst| inst.port)
+                    .take(config.instances)
+                    .collect();
+                let new_ports = instance::free_ports(config.instances - ports.len())?;
+
+                if !new_ports.is_empty() {
+                    println!("Using ports {}.", format_ports(&new_ports));
+                }
+                ports.extend(new_ports);
+                ports
+            }
+        };
+
+        // Instances which failed can't be restarted, so they are replaced instead.
+        self.instances.retain(|inst| {
+            inst.port.is_some_and(|port| ports.contains(&port))
+                && inst.status.get() != Status::Failed
+        });
+
+        let new_ports: Vec<u16> = ports
+            .iter()
+            .copied()
+            .filter(|port| !self.instances.iter().any(|inst| inst.port == Some(*port)))
+            .collect();
+
+        let changed: Vec<InstanceHandle> = self
+            .instances
+            .iter()
+            .filter(|inst| !inst.runs_with(config))
+            .filter_map(Instance::handle)
+            .collect();
+
+        let new_urls: Vec<String> = new_ports.iter().copied().map(instance::local_url).collect();
+
+        if servers_are_online(&*self.backend, &self.client, &new_urls)
+            .await?
+            .into_iter()
+            .any(|x| x)
+        {
+            bail!("A Kobold server is already running on at least one specified port!");
+        }
+
+        // New instances take the lowest log names which aren't in use.
+        let log_names: Vec<String> = (0..)
+            .map(|i| logs::log_name(&self.name, i))
+            .filter(|name| {
+                !self
+                    .instances
+                    .iter()
+                    .any(|inst| inst.log_name.as_ref() == Some(name))
+            })
+            .take(new_ports.len())
+            .collect();
+
+        let mut waiting = new_ports.clone();
+
+        for (port, log_name) in new_ports.into_iter().zip(log_names) {
+            self.instances.push(Instance::spawn(
+                self.backend.clone(),
+                self.client.clone(),
+                config,
+                log_name,
+                port,
+            )?);
+        }
+        self.instances.sort_by_key(|inst| inst.port);
+
+        if config.rolling_restart && !changed.is_empty() {
+            self.rollout = Some(AbortOnDrop(tokio::spawn(roll_out(changed, config.clone()))));
+        } else {
+            for handle in &changed {
+                handle.restart(config);
+                waiting.push(handle.port);
+            }
+        }
+
+        let waiting: Vec<&Instance> = self
+            .instances
+            .iter()
+            .filter(|inst| inst.port.is_some_and(|port| waiting.contains(&port)))
+            .collect();
+
+        wait_until_ready(&waiting, config.startup_timeout).await
+    }
+
+    async fn reconcile_urls(&mut self, config: &ServerConfig) -> Result<()> {
+        let urls = normalized_urls(config)?;
+        let mut old = std::mem::take(&mut self.instances);
+
+        let new_urls: Vec<String> = urls
+            .iter()
+            .filter(|url| !old.iter().any(|inst| inst.url == **url))
+            .cloned()
+            .collect();
+        let online = servers_are_online(&*self.backend, &self.client, &new_urls).await?;
+
+        for url in urls {
+            if let Some(i) = old.iter().position(|inst| inst.url == url) {
+                self.instances.push(old.swap_remove(i));
+                continue;
+            }
+
+            let online = new_urls
+                .iter()
+                .zip(&online)
+                .any(|(new, online)| *new == url && *online);
+            let instance = Instance::attach(self.backend.clone(), self.client.clone(), url, online);
+
+            if !online {
+                println!(
+                    "{} is offline, and won't be used until it responds.",
+                    instance.name()
+                );
+            }
+            self.instances.push(instance);
+        }
+
+        Ok(())
+    }
+}
+
+/// Restarts instances one at a time, waiting for each to come online before moving on, so that
+/// the others keep serving in the meantime.
+async fn roll_out(handles: Vec<InstanceHandle>, config: ServerConfig) {
+    for handle in handles {
+        println!("{} is restarting with new settings...", handle.name);
+        handle.restart(&config);
+
+        let start = Instant::now();
+
+        while handle.status() == Status::Starting {
+            if config.startup_timeout != 0
+                && start.elapsed() > Duration::from_secs(config.startup_timeout)
+            {
+                eprintln!(
+                    "{} did not come online within {} seconds; stopping the rolling restart.",
+                    handle.name, config.startup_timeout
+                );
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        if handle.status() == Status::Failed {
+            eprintln!(
+                "Stopping the rolling restart, since {} failed.",
+                handle.name
+            );
+            return;
+        }
+    }
+
+    println!("Rolling restart done.");
+}
@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::instance::Instance;
+use crate::files::RoutingConfig;
+
+/// The last prompt sent to an instance, which approximates the contents of its KV cache. The
+/// default is an empty cache, which is also assumed when the contents are unknown.
+#[derive(Clone, Debug)]
+pub(super) struct LastPrompt {
+    pub(super) text: String,
+    pub(super) tokens: Option<Vec<u32>>,
+    pub(super) time: Instant,
+}
+
+impl Default for LastPrompt {
+    fn default() -> Self {
+        Self {
+            text: String::new(),
+            tokens: Some(Vec::new()),
+            time: Instant::now(),
+        }
+    }
+}
+
+/// Counts the requests an instance is currently processing.
+#[derive(Clone, Debug, Default)]
+pub(super) struct BusyCounter(Arc<AtomicUsize>);
+
+/// Marks an instance as busy until dropped.
+#[derive(Debug)]
+pub(super) struct BusyGuard(Arc<AtomicUsize>);
+
+impl BusyCounter {
+    pub(super) fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn acquire(&self) -> BusyGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        BusyGuard(self.0.clone())
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the number of elements which must be parsed and erased to go from `last_prompt` to
+/// `new_prompt`.
+fn prefix_diff<T: PartialEq>(last_prompt: &[T], new_prompt: &[T]) -> (usize, usize) {
+    let shared_prefix_length = last_prompt
+        .iter()
+        .zip(new_prompt)
+        .position(|(p1, p2)| p1 != p2)
+        .unwrap_or(last_prompt.len().min(new_prompt.len()));
+
+    (
+        new_prompt.len() - shared_prefix_length,
+        last_prompt.len() - shared_prefix_length,
+    )
+}
+
+/// Assigns prompts to instances, keeping each chat on its "home" instance where possible.
+#[derive(Debug, Default)]
+pub(super) struct Router {
+    config: RoutingConfig,
+    homes: HashMap<String, usize>,
+}
+
+impl Router {
+    pub(super) fn new(config: RoutingConfig) -> Self {
+        Self {
+            config,
+            homes: HashMap::new(),
+        }
+    }
+
+    /// Halves the weight of a prompt every `erase_half_life` seconds.
+    fn decay(&self, age: Duration) -> f64 {
+        0.5f64.powf(age.as_secs_f64() / self.config.erase_half_life)
+    }
+
+    /// Estimates the cost of processing `prompt` for `chat` on each instance which is ready.
+    /// Instances whose last request failed are left out, unless no others are ready.
+    fn costs(
+        &self,
+        chat: &str,
+        prompt: &str,
+        tokens: Option<&[u32]>,
+        instances: &[Instance],
+    ) -> Vec<(usize, f64)> {
+        let now = Instant::now();
+        let any_healthy = instances
+            .iter()
+            .any(|inst| inst.is_ready() && !inst.last_failed());
+        let ready = || {
+            instances
+                .iter()
+                .enumerate()
+                .filter(move |(_, inst)| inst.is_ready() && !(any_healthy && inst.last_failed()))
+        };
+
+        // Only compare token counts if every instance has them, since the units differ.
+        let tokens = tokens.filter(|_| ready().all(|(_, inst)| inst.last_prompt.tokens.is_some()));
+        let age = |inst: &Instance| now.saturating_duration_since(inst.last_prompt.time);
+        // The chat's home, if it's still ready to take the chat back.
+        let home = self
+            .homes
+            .get(chat)
+            .copied()
+            .filter(|home| ready().any(|(i, _)| i == *home));
+
+        ready()
+            .map(|(i, inst)| {
+                let last = &inst.last_prompt;
+                let (parsed, erased) = match tokens {
+                    Some(tokens) => prefix_diff(last.tokens.as_deref().unwrap(), tokens),
+                    None => prefix_diff(last.text.as_bytes(), prompt.as_bytes()),
+                };
+                let decay = self.decay(age(inst));
+                let other_home = self
+                    .homes
+                    .iter()
+                    .any(|(other, home)| *home == i && other != chat);
+
+                let mut cost = parsed as f64
+                    + erased as f64 * self.config.erase_cost * decay
+                    + inst.busy.get() as f64 * self.config.busy_cost;
+
+                if other_home {
+                    cost += self.config.home_cost * decay;
+                }
+                if let Some(home) = home.filter(|home| *home != i) {
+                    cost += self.config.home_cost * self.decay(age(&instances[home]));
+                }
+
+                (i, cost)
+            })
+            .collect()
+    }
+
+    /// Picks the ready instance which can process `prompt` the fastest, if there is one. Unless
+    /// `chat` was only moved because its home instance is busy, the chosen instance becomes the
+    /// chat's new home.
+    pub(super) fn route(
+        &mut self,
+        chat: &str,
+        prompt: &str,
+        tokens: Option<&[u32]>,
+        instances: &[Instance],
+    ) -> Option<usize> {
+        let best_server = self
+            .costs(chat, prompt, tokens, instances)
+            .into_iter()
+            .min_by(|(_, x), (_, y)| x.total_cmp(y))?
+            .0;
+
+        let home_is_busy = self
+            .homes
+            .get(chat)
+            .and_then(|home| instances.get(*home))
+            .is_some_and(|home| home.is_ready() && home.busy.get() > 0);
+
+        if !home_is_busy {
+            self.homes.retain(|_, home| *home != best_server);
+            self.homes.insert(chat.to_string(), best_server);
+        }
+
+        Some(best_server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::ServerConfig;
+    use crate::server::backend::new_backend;
+
+    /// Returns a ready instance for each of `last_prompts`, which it last processed.
+    fn instances(last_prompts: &[&str]) -> Vec<Instance> {
+        let backend = new_backend(&ServerConfig::default());
+
+        last_prompts
+            .iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let url = format!("http://127.0.0.1:{}", i + 1);
+                let mut inst = Instance::attach(backend.clone(), Default::default(), url, true);
+                inst.last_prompt = LastPrompt {
+                    text: text.to_string(),
+                    tokens: None,
+                    time: Instant::now(),
+                };
+                inst
+            })
+            .collect()
+    }
+
+    /// A router whose costs don't decay during the test.
+    fn router() -> Router {
+        Router::new(RoutingConfig {
+            erase_cost: 1.0,
+            erase_half_life: 1e12,
+            busy_cost: 100.0,
+            home_cost: 10.0,
+        })
+    }
+
+    fn assert_costs(costs: Vec<(usize, f64)>, expected: &[f64]) {
+        assert_eq!(costs.len(), expected.len());
+
+        for ((_, cost), expected) in costs.iter().zip(expected) {
+            assert!((cost - expected).abs() < 1e-6, "{costs:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn prefix_diff_counts_parsed_and_erased() {
+        assert_eq!(prefix_diff(b"abc", b"abde"), (2, 1));
+        assert_eq!(prefix_diff(b"abc", b"abc"), (0, 0));
+        assert_eq!(prefix_diff(b"abc", b"ab"), (0, 1));
+        assert_eq!(prefix_diff(b"", b"abc"), (3, 0));
+        assert_eq!(prefix_diff(&[1, 2, 3], &[4]), (1, 3));
+    }
+
+    #[test]
+    fn decay_halves_every_half_life() {
+        let router = Router::new(RoutingConfig {
+            erase_half_life: 10.0,
+            ..RoutingConfig::default()
+        });
+
+        assert_eq!(router.decay(Duration::ZERO), 1.0);
+        assert_eq!(router.decay(Duration::from_secs(10)), 0.5);
+        assert_eq!(router.decay(Duration::from_secs(20)), 0.25);
+    }
+
+    #[tokio::test]
+    async fn chats_are_kept_off_other_homes() {
+        let instances = instances(&["", ""]);
+        let mut router = router();
+
+        assert_eq!(router.route("a", "abc", None, &instances), Some(0));
+        assert_costs(router.costs("b", "abc", None, &instances), &[13.0, 3.0]);
+        assert_eq!(router.route("b", "abc", None, &instances), Some(1));
+    }
+
+    #[tokio::test]
+    async fn moving_off_home_costs_home_cost() {
+        let instances = instances(&["abc", ""]);
+        let mut router = router();
+
+        router.homes.insert("a".to_string(), 0);
+
+        // Erasing the whole cache at home is cheaper than moving.
+        assert_costs(router.costs("a", "xyz", None, &instances), &[6.0, 13.0]);
+        assert_eq!(router.route("a", "xyz", None, &instances), Some(0));
+
+        // A chat without a home pays nothing extra to go anywhere but another chat's home.
+        assert_costs(router.costs("b", "xyz", None, &instances), &[16.0, 3.0]);
+    }
+}
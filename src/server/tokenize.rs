@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+
+use reqwest::Client;
+
+use super::backend::Backend;
+
+/// Maximum number of prompts kept in the token cache before it is cleared.
+const MAX_CACHED_PROMPTS: usize = 64;
+
+/// Caches token ids for recently routed prompts, so that swipes and regens on the same prompt
+/// don't hit the tokenize endpoint again.
+#[derive(Debug)]
+pub(super) struct TokenCache {
+    entries: HashMap<String, Vec<u32>>,
+    supported: bool,
+}
+
+impl Default for TokenCache {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            supported: true,
+        }
+    }
+}
+
+impl TokenCache {
+    /// Returns the token ids of `text`, or `None` if the server can't tokenize text for us.
+    pub(super) async fn tokenize(
+        &mut self,
+        backend: &dyn Backend,
+        client: &Client,
+        url: &str,
+        text: &str,
+    ) -> Option<Vec<u32>> {
+        if !self.supported {
+            return None;
+        }
+
+        if let Some(tokens) = self.entries.get(text) {
+            return Some(tokens.clone());
+        }
+
+        match backend
+            .tokenize(client.clone(), url.to_string(), text.to_string())
+            .await
+        {
+            Ok(Some(tokens)) => {
+                if self.entries.len() >= MAX_CACHED_PROMPTS {
+                    self.entries.clear();
+                }
+                self.entries.insert(text.to_string(), tokens.clone());
+                Some(tokens)
+            }
+            Ok(None) => {
+                // The server can't return token ids; stop asking.
+                self.supported = false;
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}